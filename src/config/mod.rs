@@ -1,5 +1,10 @@
 use log::{Log, Record, Level, Metadata, SetLoggerError, LevelFilter};
 use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::net::{IpAddr, SocketAddr};
+use std::{env, fs};
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+use crate::db::{MAX_INDEXING_THREADS, MIN_HEAP_PER_THREAD};
 
 
 pub struct SimpleLogger;
@@ -39,11 +44,224 @@ pub fn init_logger(level: LevelFilter, logger: &'static Log) -> Result<(), SetLo
 }
 
 
+/// server wide configuration. the defaults are overridden, in this order, by a json config file,
+/// `NIMOOL_*` environment variables and command line flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct AppConf {
-    pub index_path: &'static str,
+    pub index_path: PathBuf,
     pub writer_buff_size: usize,
-    pub listen_address: &'static str,
+    pub listen_address: String,
     pub listen_port: u16,
+    /// in seconds in the config file
+    #[serde(serialize_with = "serialize_secs", deserialize_with = "deserialize_secs")]
     pub auto_commit_interval: Duration,
+    /// upper bounds in bytes for request bodies. bulk and search requests have their own, every
+    /// other request is held to the document limit.
+    pub max_document_body_size: usize,
+    pub max_bulk_body_size: usize,
+    pub max_search_body_size: usize,
 }
 
+impl Default for AppConf {
+    fn default() -> Self {
+        Self {
+            index_path: PathBuf::from("./indexes"),
+            writer_buff_size: 50_000_000,
+            listen_address: "127.0.0.1".to_string(),
+            listen_port: 1969,
+            auto_commit_interval: Duration::from_secs(5),
+            max_document_body_size: 10 * 1024 * 1024,
+            max_bulk_body_size: 100 * 1024 * 1024,
+            max_search_body_size: 1024 * 1024,
+        }
+    }
+}
+
+fn serialize_secs<S: Serializer>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(d.as_secs())
+}
+
+fn deserialize_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+/// the writer memory the indexing threads of an index get by default need at least
+fn min_writer_buff_size() -> usize {
+    MIN_HEAP_PER_THREAD * num_cpus::get().min(MAX_INDEXING_THREADS)
+}
+
+/// the names `set` accepts, environment variables for anything else are ignored
+const SETTINGS: &[&str] = &[
+    "index_path",
+    "writer_buff_size",
+    "listen_address",
+    "listen_port",
+    "auto_commit_interval",
+    "max_document_body_size",
+    "max_bulk_body_size",
+    "max_search_body_size",
+];
+
+pub const DEFAULT_CONFIG_FILE: &str = "nimool.json";
+
+pub const USAGE: &str = "usage: rlastic_search [--config FILE] [--index-path DIR] [--writer-buff-size BYTES]
+                     [--listen-address IP] [--listen-port PORT] [--auto-commit-interval SECS]
+                     [--max-document-body-size BYTES] [--max-bulk-body-size BYTES]
+                     [--max-search-body-size BYTES]
+
+every flag can also be set through the environment, e.g. NIMOOL_LISTEN_PORT=9200.
+without --config (or NIMOOL_CONFIG) ./nimool.json is read when it exists.";
+
+impl AppConf {
+    /// loads the configuration of this process. all problems are collected so they can be reported
+    /// at once on startup.
+    pub fn load() -> Result<Self, Vec<String>> {
+        let args: Vec<String> = env::args().skip(1).collect();
+        let flags = parse_flags(&args).map_err(|e| vec![e])?;
+        let mut vars = Vec::new();
+        for (name, value) in env::vars().filter(|(k, _)| k.starts_with("NIMOOL_")) {
+            let key = name["NIMOOL_".len()..].to_lowercase();
+            if key == "config" || SETTINGS.contains(&key.as_str()) {
+                vars.push((key, value));
+            } else {
+                warn!("ignoring environment variable {}, there is no setting {}", name, key);
+            }
+        }
+
+        let config_file = flags.iter().chain(vars.iter())
+            .find(|(k, _)| k == "config")
+            .map(|(_, v)| PathBuf::from(v))
+            .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|p| p.is_file()));
+        let mut conf = match config_file {
+            Some(path) => Self::from_file(&path).map_err(|e| vec![e])?,
+            None => Self::default()
+        };
+
+        let mut errors = Vec::new();
+        for (key, value) in vars.iter().chain(flags.iter()).filter(|(k, _)| k != "config") {
+            if let Err(e) = conf.set(key, value) {
+                errors.push(e);
+            }
+        }
+        errors.extend(conf.validate());
+        if errors.is_empty() {
+            Ok(conf)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("could not read config file {}: {}", path.display(), e))?;
+        serde_json::from_str(&content)
+            .map_err(|e| format!("invalid config file {}: {}", path.display(), e))
+    }
+
+    /// sets a single setting from its string form, `key` is the field name
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let invalid = |expected: &str| format!("invalid value for {}: {}. expected {}", key, value, expected);
+        match key {
+            "index_path" => self.index_path = PathBuf::from(value),
+            "writer_buff_size" => self.writer_buff_size = value.parse().map_err(|_| invalid("a number of bytes"))?,
+            "listen_address" => self.listen_address = value.to_string(),
+            "listen_port" => self.listen_port = value.parse().map_err(|_| invalid("a port number"))?,
+            "auto_commit_interval" => {
+                self.auto_commit_interval = value.parse().map(Duration::from_secs).map_err(|_| invalid("a number of seconds"))?
+            }
+            "max_document_body_size" => self.max_document_body_size = value.parse().map_err(|_| invalid("a number of bytes"))?,
+            "max_bulk_body_size" => self.max_bulk_body_size = value.parse().map_err(|_| invalid("a number of bytes"))?,
+            "max_search_body_size" => self.max_search_body_size = value.parse().map_err(|_| invalid("a number of bytes"))?,
+            _ => return Err(format!("unknown setting: {}", key))
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let min_buff_size = min_writer_buff_size();
+        if self.writer_buff_size < min_buff_size {
+            errors.push(format!("writer_buff_size must be at least {} bytes, {} for each indexing thread",
+                                min_buff_size, MIN_HEAP_PER_THREAD));
+        }
+        if self.listen_address.parse::<IpAddr>().is_err() {
+            errors.push(format!("listen_address is not an ip address: {}", self.listen_address));
+        }
+        if self.auto_commit_interval.as_secs() == 0 {
+            errors.push("auto_commit_interval must be at least one second".to_string());
+        }
+        let limits = [
+            ("max_document_body_size", self.max_document_body_size),
+            ("max_bulk_body_size", self.max_bulk_body_size),
+            ("max_search_body_size", self.max_search_body_size),
+        ];
+        for (name, _) in limits.iter().filter(|(_, limit)| *limit == 0) {
+            errors.push(format!("{} must be greater than zero", name));
+        }
+        if self.index_path.exists() && !self.index_path.is_dir() {
+            errors.push(format!("index_path is not a directory: {}", self.index_path.display()));
+        }
+        errors
+    }
+
+    /// only valid after `validate` found no problems
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.listen_address.parse().unwrap(), self.listen_port)
+    }
+}
+
+/// turns `--listen-port 9200` and `--listen-port=9200` into `("listen_port", "9200")`
+fn parse_flags(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            return Err(format!("unexpected argument: {}\n{}", arg, USAGE));
+        }
+        let mut kv = arg[2..].splitn(2, '=');
+        let key = kv.next().unwrap_or("").replace('-', "_");
+        let value = match kv.next() {
+            Some(v) => v.to_string(),
+            None => args.next().cloned().ok_or_else(|| format!("missing value for {}\n{}", arg, USAGE))?
+        };
+        flags.push((key, value));
+    }
+    Ok(flags)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{AppConf, SETTINGS, parse_flags, min_writer_buff_size};
+
+    #[test]
+    fn test_conf_overrides() {
+        let args: Vec<String> = vec!["--listen-port", "9200", "--writer-buff-size=1000"].iter().map(|s| s.to_string()).collect();
+        let mut conf: AppConf = serde_json::from_str(r#"{"listen_address": "0.0.0.0", "auto_commit_interval": 30}"#).unwrap();
+        for (k, v) in parse_flags(&args).unwrap() {
+            conf.set(&k, &v).unwrap();
+        }
+        assert_eq!(conf.socket_addr().to_string(), "0.0.0.0:9200");
+        assert_eq!(conf.auto_commit_interval.as_secs(), 30);
+        assert_eq!(conf.validate().len(), 1);
+        assert!(conf.set("no_such_setting", "1").is_err());
+        assert!(parse_flags(&["--index-path".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_settings_and_writer_minimum() {
+        let mut conf = AppConf::default();
+        for key in SETTINGS {
+            if let Err(e) = conf.set(key, "-") {
+                assert!(!e.starts_with("unknown setting"), "{}", e);
+            }
+        }
+        let min = min_writer_buff_size();
+        assert!(min >= 3_000_000 && min <= 8 * 3_000_000);
+        let conf = AppConf { writer_buff_size: min - 1, ..AppConf::default() };
+        assert_eq!(conf.validate().len(), 1);
+        let conf = AppConf { writer_buff_size: min, ..AppConf::default() };
+        assert!(conf.validate().is_empty());
+    }
+}
@@ -0,0 +1,584 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use tantivy::tokenizer::{
+    Token,
+    TokenStream,
+    TokenFilter,
+    Tokenizer,
+    TokenizerManager,
+    SimpleTokenizer,
+    RawTokenizer,
+    LowerCaser,
+    StopWordFilter,
+    Stemmer,
+    Language,
+    BoxedTokenizer,
+};
+use tantivy::Index;
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, QueryParserError, TermQuery};
+use tantivy::schema::{Schema, Field, FieldType as TFieldType, IndexRecordOption, Term};
+
+mod persian;
+mod synonyms;
+
+pub use self::synonyms::{SynonymSets, SynonymMap};
+
+//every token turns into up to this many grams per character
+const MAX_NGRAM_DIFF: usize = 10;
+
+
+/// splits the text into tokens, the first step of an analyzer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TokenizerDef {
+    /// on everything that is not alphanumeric, like tantivy's `default`
+    Simple,
+    Whitespace,
+    /// the whole text is one token
+    Raw,
+    /// like `Simple`, but zero width non-joiners, tatweel and diacritics inside a word do not split it
+    Persian,
+}
+
+impl Default for TokenizerDef {
+    fn default() -> Self {
+        TokenizerDef::Simple
+    }
+}
+
+/// a step of the filter chain of an analyzer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FilterDef {
+    Lowercase,
+    /// removes the given words, the filter only sees what the filters before it left, so a
+    /// lowercase filter usually comes first
+    Stop(Vec<String>),
+    Stemmer(Language),
+    /// replaces accented latin letters by their ascii counterpart
+    AsciiFolding,
+    /// drops tokens with fewer than `min` or more than `max` characters
+    Length { min: usize, max: usize },
+    /// replaces every token by its substrings of `min_gram` to `max_gram` characters
+    Ngram { min_gram: usize, max_gram: usize },
+    /// replaces every token by its prefixes of `min_gram` to `max_gram` characters, for search as
+    /// you type. queries on a field without a search analyzer are analyzed without the ngram
+    /// filters of its analyzer, see `search_analyzers`.
+    EdgeNgram { min_gram: usize, max_gram: usize },
+    /// unifies arabic and persian letter variants and digits, drops diacritics and half-spaces
+    PersianNormalization,
+    /// strips plural and a few other suffixes from persian words, expects normalized tokens
+    PersianStemmer,
+    /// adds or substitutes the synonyms from the named synonym set of the index, see `SynonymMap`.
+    /// the set can be changed at any time, a set that does not exist yet leaves tokens alone.
+    /// `match` queries and the single word terms of query strings expand synonyms. phrases are
+    /// analyzed without this filter and find synonyms only when the documents were indexed with them.
+    Synonyms(String),
+}
+
+/// an analyzer defined in the settings of an index: a tokenizer followed by filters, applied in
+/// order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnalyzerDef {
+    #[serde(default)]
+    pub tokenizer: TokenizerDef,
+    #[serde(default)]
+    pub filters: Vec<FilterDef>,
+}
+
+impl AnalyzerDef {
+    pub fn validate(&self, name: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            errors.push(format!("analyzer name must only contain letters, digits and '_': {:?}", name));
+        }
+        if builtin_tokenizers().get(name).is_some() {
+            errors.push(format!("analyzer {} has the name of a built-in one", name));
+        }
+        for f in &self.filters {
+            match f {
+                FilterDef::Length { min, max } if min > max || *max == 0 => {
+                    errors.push(format!("length filter of analyzer {} needs 0 <= min <= max and max > 0", name));
+                }
+                FilterDef::Ngram { min_gram, max_gram } | FilterDef::EdgeNgram { min_gram, max_gram } if *min_gram == 0 || min_gram > max_gram => {
+                    errors.push(format!("ngram filter of analyzer {} needs 0 < min_gram <= max_gram", name));
+                }
+                FilterDef::Ngram { min_gram, max_gram } if max_gram - min_gram > MAX_NGRAM_DIFF => {
+                    errors.push(format!("ngram filter of analyzer {} can not have max_gram - min_gram greater than {}", name, MAX_NGRAM_DIFF));
+                }
+                FilterDef::Synonyms(set) if set.is_empty() => {
+                    errors.push(format!("synonym filter of analyzer {} needs the name of a synonym set", name));
+                }
+                _ => {}
+            }
+        }
+        errors
+    }
+
+    fn without<F: Fn(&FilterDef) -> bool>(&self, drop: F) -> Self {
+        Self {
+            tokenizer: self.tokenizer.clone(),
+            filters: self.filters.iter().filter(|f| !drop(f)).cloned().collect(),
+        }
+    }
+}
+
+
+/// the analyzers every index has: tantivy's own and the ones nimool adds
+pub fn builtin_tokenizers() -> TokenizerManager {
+    let manager = TokenizerManager::default();
+    register_builtins(&manager);
+    manager
+}
+
+fn register_builtins(manager: &TokenizerManager) {
+    let no_synonyms = SynonymSets::default();
+    manager.register("persian", Analyzer::new(&persian::analyzer(false), &no_synonyms));
+    manager.register("persian_stem", Analyzer::new(&persian::analyzer(true), &no_synonyms));
+}
+
+//suffixes of the names an analyzer is registered under a second time, without its synonyms or its
+//ngram filters. analyzer names can not contain them.
+const WITHOUT_SYNONYMS: &str = "#without_synonyms";
+const WITHOUT_NGRAMS: &str = "#without_ngrams";
+
+/// registers the analyzers of an index in its tokenizer manager. this has to happen before the
+/// index writer is created, tantivy looks tokenizers up by name whenever it needs one.
+pub fn register(manager: &TokenizerManager, analyzers: &BTreeMap<String, AnalyzerDef>, synonyms: &SynonymSets) {
+    register_builtins(manager);
+    for (name, def) in analyzers {
+        register_with_literal(manager, name, def, synonyms);
+        let query = def.without(|f| match f {
+            FilterDef::Ngram { .. } | FilterDef::EdgeNgram { .. } => true,
+            _ => false
+        });
+        if query.filters.len() < def.filters.len() {
+            register_with_literal(manager, &format!("{}{}", name, WITHOUT_NGRAMS), &query, synonyms);
+        }
+    }
+}
+
+fn register_with_literal(manager: &TokenizerManager, name: &str, def: &AnalyzerDef, synonyms: &SynonymSets) {
+    manager.register(name, Analyzer::new(def, synonyms));
+    let literal = def.without(|f| match f {
+        FilterDef::Synonyms(_) => true,
+        _ => false
+    });
+    if literal.filters.len() < def.filters.len() {
+        manager.register(&format!("{}{}", name, WITHOUT_SYNONYMS), Analyzer::new(&literal, synonyms));
+    }
+}
+
+/// the built-in analyzers along with the given ones, for checking a mapping against
+pub fn tokenizers_with(analyzers: &BTreeMap<String, AnalyzerDef>) -> TokenizerManager {
+    let manager = TokenizerManager::default();
+    register(&manager, analyzers, &SynonymSets::default());
+    manager
+}
+
+
+/// what queries are analyzed with: the schema of the index, except that text fields with a search
+/// analyzer name that one instead of their index analyzer, and fields without one name their index
+/// analyzer without its ngram filters. a query would otherwise match every word sharing a gram with
+/// it. tantivy's query parser and the phrase query look tokenizers up through the schema, so they
+/// pick it up without knowing about it.
+/// tantivy makes a phrase out of the words at the same position, so query strings and phrases are
+/// analyzed without synonyms, see `FilterDef::Synonyms`. the single word terms of a query string
+/// get their synonyms added afterwards.
+pub struct SearchAnalyzers {
+    schema: Schema,
+    //the schema again with synonym filters left out, for query strings and phrases
+    literal_schema: Schema,
+    tokenizers: TokenizerManager,
+}
+
+impl SearchAnalyzers {
+    /// `search_analyzers` maps field names to analyzer names
+    pub fn new(index: &Index, search_analyzers: &BTreeMap<String, String>) -> Self {
+        let tokenizers = index.tokenizers().clone();
+        let schema = with_tokenizers(&index.schema(), |field, tokenizer| {
+            let query = format!("{}{}", tokenizer, WITHOUT_NGRAMS);
+            search_analyzers.get(field).cloned().or_else(|| tokenizers.get(&query).map(|_| query))
+        });
+        let literal_schema = with_tokenizers(&schema, |_, tokenizer| {
+            let literal = format!("{}{}", tokenizer, WITHOUT_SYNONYMS);
+            tokenizers.get(&literal).map(|_| literal)
+        });
+        Self { schema, literal_schema, tokenizers }
+    }
+
+    /// queries analyzed the way documents are, apart from ngram filters
+    pub fn for_index(index: &Index) -> Self {
+        Self::new(index, &BTreeMap::new())
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// the analyzer of `match` queries
+    pub fn tokenizer_for_field(&self, field: Field) -> Option<Box<dyn BoxedTokenizer>> {
+        tokenizer_in(&self.schema, &self.tokenizers, field)
+    }
+
+    /// the analyzer of phrases, without synonyms
+    pub fn phrase_tokenizer_for_field(&self, field: Field) -> Option<Box<dyn BoxedTokenizer>> {
+        tokenizer_in(&self.literal_schema, &self.tokenizers, field)
+    }
+
+    /// parses a query string searching `default_fields` when it names no field
+    pub fn parse_query(&self, default_fields: Vec<Field>, exp: &str) -> Result<Box<dyn Query>, QueryParserError> {
+        let parser = QueryParser::new(self.literal_schema.clone(), default_fields, self.tokenizers.clone());
+        parser.parse_query(exp).map(|q| self.with_synonyms(q))
+    }
+
+    /// `query` with every term query of a text field turned into a should query of the term and
+    /// its synonyms. terms the search analyzer does not leave as they are (e.g. stemmed again) are
+    /// kept alone.
+    fn with_synonyms(&self, query: Box<dyn Query>) -> Box<dyn Query> {
+        if let Some(q) = query.downcast_ref::<BooleanQuery>() {
+            let clauses: Vec<(Occur, Box<dyn Query>)> = q.clauses().iter().map(|(occur, q)| (*occur, self.with_synonyms(q.box_clone()))).collect();
+            return Box::new(BooleanQuery::from(clauses));
+        }
+        let term = match query.downcast_ref::<TermQuery>() {
+            Some(q) => q.term().clone(),
+            None => return query
+        };
+        let tokenizer = match self.tokenizer_for_field(term.field()) {
+            Some(tokenizer) => tokenizer,
+            None => return query
+        };
+        let mut words: Vec<String> = Vec::new();
+        let mut stream = tokenizer.token_stream(term.text());
+        while stream.advance() {
+            if !words.contains(&stream.token().text) {
+                words.push(stream.token().text.clone());
+            }
+        }
+        if words.len() < 2 || !words.iter().any(|w| w == term.text()) {
+            return query;
+        }
+        let clauses: Vec<(Occur, Box<dyn Query>)> = words.iter().map(|w| {
+            let q: Box<dyn Query> = Box::new(TermQuery::new(Term::from_field_text(term.field(), w), IndexRecordOption::WithFreqs));
+            (Occur::Should, q)
+        }).collect();
+        Box::new(BooleanQuery::from(clauses))
+    }
+}
+
+fn tokenizer_in(schema: &Schema, tokenizers: &TokenizerManager, field: Field) -> Option<Box<dyn BoxedTokenizer>> {
+    match schema.get_field_entry(field).field_type() {
+        TFieldType::Str(opts) => opts.get_indexing_options().and_then(|o| tokenizers.get(o.tokenizer())),
+        _ => None
+    }
+}
+
+/// `schema` with the tokenizer of indexed text fields replaced by what `replace` returns for the
+/// field name and the current tokenizer, if anything
+fn with_tokenizers<F: Fn(&str, &str) -> Option<String>>(schema: &Schema, replace: F) -> Schema {
+    let replacements: Vec<Option<String>> = schema.fields().iter().map(|entry| match entry.field_type() {
+        TFieldType::Str(opts) => opts.get_indexing_options().and_then(|o| replace(entry.name(), o.tokenizer())),
+        _ => None
+    }).collect();
+    if replacements.iter().all(Option::is_none) {
+        return schema.clone();
+    }
+    let mut builder = Schema::builder();
+    //fields are added in the same order, so they keep their ids
+    for (entry, replacement) in schema.fields().iter().zip(replacements) {
+        let name = entry.name();
+        match entry.field_type() {
+            TFieldType::Str(opts) => {
+                let opts = match (opts.get_indexing_options(), replacement) {
+                    (Some(indexing), Some(tokenizer)) => opts.clone().set_indexing_options(indexing.clone().set_tokenizer(&tokenizer)),
+                    _ => opts.clone()
+                };
+                builder.add_text_field(name, opts)
+            }
+            TFieldType::U64(opts) => builder.add_u64_field(name, opts.clone()),
+            TFieldType::I64(opts) => builder.add_i64_field(name, opts.clone()),
+            TFieldType::Date(opts) => builder.add_date_field(name, opts.clone()),
+            TFieldType::HierarchicalFacet => builder.add_facet_field(name),
+            TFieldType::Bytes => builder.add_bytes_field(name),
+        };
+    }
+    builder.build()
+}
+
+
+#[derive(Clone)]
+enum Filter {
+    Lowercase,
+    Stop(StopWordFilter),
+    Stem(Stemmer),
+    AsciiFolding,
+    Length { min: usize, max: usize },
+    Ngram { min_gram: usize, max_gram: usize, edge: bool },
+    PersianNormalization,
+    PersianStemmer,
+    Synonyms { set: String, sets: SynonymSets },
+}
+
+impl Filter {
+    fn new(def: &FilterDef, synonyms: &SynonymSets) -> Self {
+        match def {
+            FilterDef::Lowercase => Filter::Lowercase,
+            FilterDef::Stop(words) => Filter::Stop(StopWordFilter::remove(words.clone())),
+            FilterDef::Stemmer(lang) => Filter::Stem(Stemmer::new(*lang)),
+            FilterDef::AsciiFolding => Filter::AsciiFolding,
+            FilterDef::Length { min, max } => Filter::Length { min: *min, max: *max },
+            FilterDef::Ngram { min_gram, max_gram } => Filter::Ngram { min_gram: *min_gram, max_gram: *max_gram, edge: false },
+            FilterDef::EdgeNgram { min_gram, max_gram } => Filter::Ngram { min_gram: *min_gram, max_gram: *max_gram, edge: true },
+            FilterDef::PersianNormalization => Filter::PersianNormalization,
+            FilterDef::PersianStemmer => Filter::PersianStemmer,
+            FilterDef::Synonyms(set) => Filter::Synonyms { set: set.clone(), sets: synonyms.clone() },
+        }
+    }
+
+    fn apply(&self, tokens: Vec<Token>) -> Vec<Token> {
+        match self {
+            Filter::Lowercase => through(&LowerCaser, tokens),
+            Filter::Stop(f) => through(f, tokens),
+            Filter::Stem(f) => through(f, tokens),
+            Filter::AsciiFolding => tokens.into_iter().map(|mut t| {
+                if !t.text.is_ascii() {
+                    t.text = fold_to_ascii(&t.text);
+                }
+                t
+            }).collect(),
+            Filter::Length { min, max } => tokens.into_iter().filter(|t| {
+                let len = t.text.chars().count();
+                len >= *min && len <= *max
+            }).collect(),
+            Filter::Ngram { min_gram, max_gram, edge } => tokens.iter()
+                .flat_map(|t| ngrams(t, *min_gram, *max_gram, *edge))
+                .collect(),
+            Filter::PersianNormalization => tokens.into_iter().map(|mut t| {
+                t.text = persian::normalize(&t.text);
+                t
+            }).collect(),
+            Filter::PersianStemmer => tokens.into_iter().map(|mut t| {
+                if let Some(stem) = persian::stem(&t.text) {
+                    t.text = stem;
+                }
+                t
+            }).collect(),
+            //synonyms take the position and offsets of the word they stand for
+            Filter::Synonyms { set, sets } => match sets.get(set) {
+                Some(map) => tokens.into_iter().flat_map(|t| match map.expand(&t.text) {
+                    Some(words) => words.iter().map(|w| Token { text: w.clone(), ..t.clone() }).collect(),
+                    None => vec![t],
+                }).collect(),
+                None => tokens
+            },
+        }
+    }
+}
+
+/// the grams of a token, at the position and with the offsets of the token itself. tokens shorter
+/// than `min_gram` are dropped.
+fn ngrams(token: &Token, min_gram: usize, max_gram: usize, edge: bool) -> Vec<Token> {
+    let bounds: Vec<usize> = token.text.char_indices().map(|(i, _)| i).chain(std::iter::once(token.text.len())).collect();
+    let chars = bounds.len() - 1;
+    let starts = if edge { 0..1.min(chars) } else { 0..chars };
+    let mut grams = Vec::new();
+    for start in starts {
+        for len in min_gram..=max_gram.min(chars - start) {
+            let mut gram = token.clone();
+            gram.text = token.text[bounds[start]..bounds[start + len]].to_string();
+            grams.push(gram);
+        }
+    }
+    grams
+}
+
+/// runs a tantivy token filter over buffered tokens
+fn through<F: TokenFilter<BufferedTokens>>(filter: &F, tokens: Vec<Token>) -> Vec<Token> {
+    let mut stream = filter.transform(BufferedTokens::new(tokens));
+    let mut out = Vec::new();
+    while stream.advance() {
+        out.push(stream.token().clone());
+    }
+    out
+}
+
+/// a tokenizer and a filter chain that are only known at runtime. tantivy builds its chains out of
+/// generic types, so here the tokens are buffered and every filter runs over all of them.
+#[derive(Clone)]
+pub struct Analyzer {
+    tokenizer: TokenizerDef,
+    filters: Vec<Filter>,
+}
+
+impl Analyzer {
+    pub fn new(def: &AnalyzerDef, synonyms: &SynonymSets) -> Self {
+        Self {
+            tokenizer: def.tokenizer.clone(),
+            filters: def.filters.iter().map(|f| Filter::new(f, synonyms)).collect(),
+        }
+    }
+
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        match self.tokenizer {
+            TokenizerDef::Simple => collect(SimpleTokenizer.token_stream(text)),
+            TokenizerDef::Raw => collect(RawTokenizer.token_stream(text)),
+            TokenizerDef::Whitespace => split_tokens(text, not_whitespace, not_whitespace),
+            TokenizerDef::Persian => split_tokens(text, char::is_alphanumeric, persian::continues_word),
+        }
+    }
+}
+
+impl<'a> Tokenizer<'a> for Analyzer {
+    type TokenStreamImpl = BufferedTokens;
+
+    fn token_stream(&self, text: &'a str) -> BufferedTokens {
+        let tokens = self.filters.iter().fold(self.tokenize(text), |tokens, f| f.apply(tokens));
+        BufferedTokens::new(tokens)
+    }
+}
+
+fn collect<S: TokenStream>(mut stream: S) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    while stream.advance() {
+        tokens.push(stream.token().clone());
+    }
+    tokens
+}
+
+/// splits the text into runs of characters, a run starts on a character `starts` accepts and goes
+/// on while `continues` accepts them
+fn split_tokens(text: &str, starts: fn(char) -> bool, continues: fn(char) -> bool) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match start {
+            None if i < text.len() && starts(c) => start = Some(i),
+            Some(from) if i == text.len() || !continues(c) => {
+                tokens.push(Token {
+                    offset_from: from,
+                    offset_to: i,
+                    position: tokens.len(),
+                    text: text[from..i].to_string(),
+                    position_length: 1,
+                });
+                start = if i < text.len() && starts(c) { Some(i) } else { None };
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+fn not_whitespace(c: char) -> bool {
+    !c.is_whitespace()
+}
+
+/// tokens that were produced up front, handed out one by one
+pub struct BufferedTokens {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl BufferedTokens {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        Self { tokens, next: 0 }
+    }
+}
+
+impl TokenStream for BufferedTokens {
+    fn advance(&mut self) -> bool {
+        if self.next < self.tokens.len() {
+            self.next += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.next - 1]
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.next - 1]
+    }
+}
+
+
+const ASCII_FOLDING: &[(&str, &str)] = &[
+    ("ÀÁÂÃÄÅĀĂĄ", "A"), ("àáâãäåāăą", "a"),
+    ("ÇĆĈĊČ", "C"), ("çćĉċč", "c"),
+    ("ÐĎĐ", "D"), ("ðďđ", "d"),
+    ("ÈÉÊËĒĔĖĘĚ", "E"), ("èéêëēĕėęě", "e"),
+    ("ĜĞĠĢ", "G"), ("ĝğġģ", "g"),
+    ("ĤĦ", "H"), ("ĥħ", "h"),
+    ("ÌÍÎÏĨĪĬĮİ", "I"), ("ìíîïĩīĭįı", "i"),
+    ("Ĵ", "J"), ("ĵ", "j"),
+    ("Ķ", "K"), ("ķ", "k"),
+    ("ĹĻĽĿŁ", "L"), ("ĺļľŀł", "l"),
+    ("ÑŃŅŇ", "N"), ("ñńņň", "n"),
+    ("ÒÓÔÕÖØŌŎŐ", "O"), ("òóôõöøōŏő", "o"),
+    ("ŔŖŘ", "R"), ("ŕŗř", "r"),
+    ("ŚŜŞŠ", "S"), ("śŝşš", "s"),
+    ("ŢŤŦ", "T"), ("ţťŧ", "t"),
+    ("ÙÚÛÜŨŪŬŮŰŲ", "U"), ("ùúûüũūŭůűų", "u"),
+    ("Ŵ", "W"), ("ŵ", "w"),
+    ("ÝŶŸ", "Y"), ("ýÿŷ", "y"),
+    ("ŹŻŽ", "Z"), ("źżž", "z"),
+    ("Æ", "AE"), ("æ", "ae"), ("Œ", "OE"), ("œ", "oe"), ("ß", "ss"), ("Þ", "TH"), ("þ", "th"),
+];
+
+fn fold_to_ascii(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match ASCII_FOLDING.iter().find(|(from, _)| from.contains(c)) {
+            Some((_, to)) if !c.is_ascii() => out.push_str(to),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use tantivy::tokenizer::Language;
+    use super::{AnalyzerDef, TokenizerDef, FilterDef, tokenizers_with};
+
+    #[test]
+    fn test_custom_analyzer() {
+        let def = AnalyzerDef {
+            tokenizer: TokenizerDef::Whitespace,
+            filters: vec![
+                FilterDef::Lowercase,
+                FilterDef::Stop(vec!["the".to_string(), "is".to_string()]),
+                FilterDef::AsciiFolding,
+                FilterDef::Stemmer(Language::English),
+                FilterDef::Length { min: 2, max: 10 },
+            ],
+        };
+        let json = serde_json::to_string(&def).unwrap();
+        assert_eq!(serde_json::from_str::<AnalyzerDef>(&json).unwrap(), def);
+
+        let mut analyzers = BTreeMap::new();
+        analyzers.insert("english_folded".to_string(), def);
+        let analyzer = tokenizers_with(&analyzers).get("english_folded").unwrap();
+        let mut tokens = Vec::new();
+        analyzer.token_stream("The  Café is running a quickly").process(&mut |t| tokens.push((t.text.clone(), t.position, t.offset_from)));
+        assert_eq!(tokens, vec![("cafe".to_string(), 1, 5), ("run".to_string(), 3, 14), ("quick".to_string(), 5, 24)]);
+    }
+
+    #[test]
+    fn test_ngram_filters() {
+        let grams = |filter: FilterDef| {
+            let mut analyzers = BTreeMap::new();
+            analyzers.insert("grams".to_string(), AnalyzerDef { tokenizer: TokenizerDef::Simple, filters: vec![FilterDef::Lowercase, filter] });
+            let mut tokens = Vec::new();
+            tokenizers_with(&analyzers).get("grams").unwrap().token_stream("Ab Äpfel").process(&mut |t| tokens.push((t.text.clone(), t.position)));
+            tokens
+        };
+        let owned = |v: Vec<(&str, usize)>| v.into_iter().map(|(t, p)| (t.to_string(), p)).collect::<Vec<_>>();
+        assert_eq!(grams(FilterDef::EdgeNgram { min_gram: 2, max_gram: 4 }), owned(vec![("ab", 0), ("äp", 1), ("äpf", 1), ("äpfe", 1)]));
+        assert_eq!(grams(FilterDef::Ngram { min_gram: 3, max_gram: 3 }), owned(vec![("äpf", 1), ("pfe", 1), ("fel", 1)]));
+    }
+}
@@ -0,0 +1,94 @@
+use super::{AnalyzerDef, TokenizerDef, FilterDef};
+
+
+/// written in normalized form, the stop filter runs after the normalization
+const STOP_WORDS: &[&str] = &[
+    "و", "در", "به", "از", "که", "این", "را", "با", "است", "برای", "آن", "یک", "خود", "تا",
+    "کرد", "بر", "هم", "نیز", "می", "نمی", "شود", "وی", "شد", "دارد", "ما", "اما", "یا", "شده",
+    "باید", "هر", "آنها", "بود", "او", "دیگر", "همه", "کند", "ها", "ای", "بین", "پس", "اگر",
+    "همین", "چه", "من", "تو", "شما", "ایشان", "آنان", "اینکه", "چون", "ولی", "بی", "نه", "کنند",
+    "باشد", "هستند", "نیست", "بوده", "داشت", "کنیم", "کردن", "کرده", "کنم", "روی", "زیر", "پیش",
+    "بعد", "قبل", "چند", "همچنین", "حتی", "هیچ", "اینها", "آنچه", "چنین", "چنان", "زیرا", "سپس",
+    "توسط", "طی", "درباره", "وقتی", "هنوز", "مثل", "مانند", "اند", "ام", "اش",
+];
+
+/// suffixes the light stemmer strips, longest first, along with how many letters have to be left
+const SUFFIXES: &[(&str, usize)] = &[
+    ("هایی", 2),
+    ("های", 2),
+    ("ها", 2),
+    ("ترین", 3),
+    ("ات", 3),
+];
+
+/// the built-in `persian` analyzer, `persian_stem` when `stem` is set
+pub fn analyzer(stem: bool) -> AnalyzerDef {
+    let mut filters = vec![
+        FilterDef::Lowercase,
+        FilterDef::PersianNormalization,
+        FilterDef::Stop(STOP_WORDS.iter().map(|w| w.to_string()).collect()),
+    ];
+    if stem {
+        filters.push(FilterDef::PersianStemmer);
+    }
+    AnalyzerDef {
+        tokenizer: TokenizerDef::Persian,
+        filters,
+    }
+}
+
+/// characters that are written inside words but carry no meaning for search: zero width
+/// (non-)joiners, tatweel and the arabic diacritics
+fn is_ignorable(c: char) -> bool {
+    match c {
+        '\u{200C}' | '\u{200D}' | '\u{0640}' | '\u{0670}' => true,
+        '\u{064B}'..='\u{065F}' | '\u{06D6}'..='\u{06ED}' => true,
+        _ => false
+    }
+}
+
+pub fn continues_word(c: char) -> bool {
+    c.is_alphanumeric() || is_ignorable(c)
+}
+
+pub fn normalize(text: &str) -> String {
+    text.chars()
+        .filter(|c| !is_ignorable(*c))
+        .map(|c| match c {
+            'ي' | 'ى' => 'ی',
+            'ك' => 'ک',
+            'ة' | 'ۀ' => 'ه',
+            'أ' | 'إ' | 'ٱ' => 'ا',
+            '۰'..='۹' => (b'0' + (c as u32 - '۰' as u32) as u8) as char,
+            '٠'..='٩' => (b'0' + (c as u32 - '٠' as u32) as u8) as char,
+            c => c
+        })
+        .collect()
+}
+
+/// the word without its suffix, `None` when there is nothing to strip
+pub fn stem(word: &str) -> Option<String> {
+    SUFFIXES.iter()
+        .find(|(suffix, min)| word.ends_with(suffix) && word.chars().count() >= suffix.chars().count() + min)
+        .map(|(suffix, _)| word[..word.len() - suffix.len()].to_string())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::builtin_tokenizers;
+
+    #[test]
+    fn test_persian_analyzer() {
+        let tokenizers = builtin_tokenizers();
+        let tokens = |name: &str, text: &str| {
+            let mut tokens = Vec::new();
+            tokenizers.get(name).unwrap().token_stream(text).process(&mut |t| tokens.push(t.text.clone()));
+            tokens
+        };
+        //arabic yeh and kaf, a half-space, a diacritic, persian digits and a stop word
+        let text = "كتاب‌هاي علمي در سال ۱۴۰۲ نوشتهٔ مُحمد";
+        assert_eq!(tokens("persian", text), vec!["کتابهای", "علمی", "سال", "1402", "نوشته", "محمد"]);
+        assert_eq!(tokens("persian_stem", text), vec!["کتاب", "علمی", "سال", "1402", "نوشته", "محمد"]);
+    }
+}
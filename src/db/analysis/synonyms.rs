@@ -0,0 +1,111 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+
+/// the synonyms of one set, by the word they replace. a rule is either a list of equivalent words,
+/// `laptop, notebook`, where every word stands for all of them, or an explicit mapping,
+/// `i-pod, ipod => ipod`, where the words on the left are replaced by the ones on the right.
+/// words are compared with the tokens as the filters before the synonym filter left them.
+#[derive(Debug, Default)]
+pub struct SynonymMap {
+    words: HashMap<String, Vec<String>>,
+}
+
+impl SynonymMap {
+    pub fn parse(rules: &[String]) -> Result<Self, String> {
+        let mut words: HashMap<String, Vec<String>> = HashMap::new();
+        for rule in rules {
+            let (from, to) = match rule.split("=>").collect::<Vec<_>>().as_slice() {
+                [equivalent] => (parse_words(equivalent, rule)?, parse_words(equivalent, rule)?),
+                [from, to] => (parse_words(from, rule)?, parse_words(to, rule)?),
+                _ => return Err(format!("rule has more than one =>: {}", rule))
+            };
+            for word in from {
+                let replacements = words.entry(word).or_insert_with(Vec::new);
+                for w in &to {
+                    if !replacements.contains(w) {
+                        replacements.push(w.clone());
+                    }
+                }
+            }
+        }
+        Ok(Self { words })
+    }
+
+    pub fn expand(&self, word: &str) -> Option<&[String]> {
+        self.words.get(word).map(Vec::as_slice)
+    }
+}
+
+fn parse_words(words: &str, rule: &str) -> Result<Vec<String>, String> {
+    let words: Vec<String> = words.split(',').map(|w| w.trim().to_string()).collect();
+    if words.iter().any(String::is_empty) {
+        return Err(format!("rule has an empty word: {}", rule));
+    }
+    //a synonym replaces a single token
+    if words.iter().any(|w| w.contains(char::is_whitespace)) {
+        return Err(format!("multi word synonyms are not supported: {}", rule));
+    }
+    Ok(words)
+}
+
+/// the synonym sets of an index, shared by all of its analyzers. replacing the sets takes effect
+/// for everything analyzed afterwards: queries right away, documents only when they are indexed.
+#[derive(Debug, Clone, Default)]
+pub struct SynonymSets(Arc<RwLock<HashMap<String, Arc<SynonymMap>>>>);
+
+impl SynonymSets {
+    pub fn new(sets: &BTreeMap<String, Vec<String>>) -> Self {
+        let synonyms = Self::default();
+        synonyms.replace_all(sets);
+        synonyms
+    }
+
+    /// sets are validated along with the settings, one that still does not parse is left out
+    pub fn replace_all(&self, sets: &BTreeMap<String, Vec<String>>) {
+        let parsed = sets.iter()
+            .filter_map(|(name, rules)| match SynonymMap::parse(rules) {
+                Ok(map) => Some((name.clone(), Arc::new(map))),
+                Err(e) => {
+                    error!("ignoring synonym set {}: {}", name, e);
+                    None
+                }
+            })
+            .collect();
+        *self.0.write().unwrap() = parsed;
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<SynonymMap>> {
+        self.0.read().unwrap().get(name).cloned()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use super::super::{AnalyzerDef, TokenizerDef, FilterDef, Analyzer};
+    use super::{SynonymSets, SynonymMap};
+    use tantivy::tokenizer::{Tokenizer, TokenStream};
+
+    #[test]
+    fn test_synonym_filter() {
+        assert!(SynonymMap::parse(&["laptop, , notebook".to_string()]).is_err());
+        assert!(SynonymMap::parse(&["lap top => laptop".to_string()]).is_err());
+
+        let sets = SynonymSets::default();
+        let def = AnalyzerDef { tokenizer: TokenizerDef::Simple, filters: vec![FilterDef::Lowercase, FilterDef::Synonyms("products".to_string())] };
+        let analyzer = Analyzer::new(&def, &sets);
+        let tokens = |text: &str| {
+            let mut tokens = Vec::new();
+            analyzer.token_stream(text).process(&mut |t| tokens.push((t.text.clone(), t.position)));
+            tokens
+        };
+        assert_eq!(tokens("Laptop"), vec![("laptop".to_string(), 0)]);
+
+        let mut rules = BTreeMap::new();
+        rules.insert("products".to_string(), vec!["laptop, notebook".to_string(), "i-pod, ipod => ipod".to_string()]);
+        sets.replace_all(&rules);
+        assert_eq!(tokens("Laptop ipod"), vec![("laptop".to_string(), 0), ("notebook".to_string(), 0), ("ipod".to_string(), 1)]);
+    }
+}
@@ -1,11 +1,13 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
 use crate::db::idx::IndexDescriptor;
-use crate::db::command::{IndexCommand, IndexCommandHandler, OpenIndexCmd, NCreateIndexCmd};
+use crate::db::command::{IndexCommand, IndexCommandHandler, OpenIndexCmd, NCreateIndexCmd, CreateIndexCmd, CloseIndexCmd};
 use crate::config::AppConf;
-use crate::db::config::IndexCreationConfig;
+use crate::db::config::{IndexCreationConfig, IndexConfig, IndexSettings};
 use crate::db::error::NimoolError;
 use std::sync::{RwLock, Arc};
+use std::fs;
+use std::io;
 use tokio::sync::mpsc::{
     self,
     UnboundedSender,
@@ -16,13 +18,22 @@ use tokio::sync::oneshot::error::RecvError;
 use tokio::sync::oneshot;
 use futures::future;
 use tantivy::Result as TantivyResul;
-use tantivy::schema::FieldType as TFieldType;
+use tantivy::TantivyError;
+use tantivy::schema::{Schema, FieldType as TFieldType};
+use serde::Deserialize;
 use futures::future::Either;
 
 
+/// the part of tantivy's meta.json the catalog reads without opening the index
+#[derive(Deserialize)]
+struct IndexMeta {
+    schema: Schema,
+}
+
 pub struct IndexCatalog<T> where T: Into<TFieldType> + Debug + Send {
     cmd_chan: UnboundedSender<IndexCommand<T>>,
     catalog: Arc<RwLock<HashMap<String, IndexDescriptor>>>,
+    app_conf: Arc<AppConf>,
 }
 
 impl<T> Clone for IndexCatalog<T> where T: Into<TFieldType> + Debug + Send {
@@ -30,20 +41,19 @@ impl<T> Clone for IndexCatalog<T> where T: Into<TFieldType> + Debug + Send {
         Self {
             cmd_chan: self.cmd_chan.clone(),
             catalog: self.catalog.clone(),
+            app_conf: self.app_conf.clone(),
         }
     }
 }
 
 impl<T> IndexCatalog<T> where T: 'static + Into<TFieldType> + Debug + Send {
-    fn spawn_receiver(rx: UnboundedReceiver<IndexCommand<T>>, catalog: Arc<RwLock<HashMap<String, IndexDescriptor>>>, cnfg: &'static AppConf) {
+    fn spawn_receiver(rx: UnboundedReceiver<IndexCommand<T>>, catalog: Arc<RwLock<HashMap<String, IndexDescriptor>>>, cnfg: Arc<AppConf>) {
         let handler = IndexCommandHandler::new(cnfg);
-        let mut shot_down_handles = Vec::new();
+        //maintainer task shutdown handles by index name, only ever touched by this task
+        let mut shut_down_handles = HashMap::new();
         let f = rx.for_each(move |cmd| {
             info!("new index command received: {:?}", cmd);
-            let res = handler.handle_command(cmd, &catalog);
-            if let Some(h) = res {
-                shot_down_handles.push(h);
-            }
+            handler.handle_command(cmd, &catalog, &mut shut_down_handles);
             Ok(())
         }).map_err(|err| {
             error!("error in receiving index command {:?}", err);
@@ -51,27 +61,66 @@ impl<T> IndexCatalog<T> where T: 'static + Into<TFieldType> + Debug + Send {
         tokio::spawn(f);
     }
 
-    pub fn new(cnfg: &'static AppConf) -> Self {
+    pub fn new(cnfg: Arc<AppConf>) -> Self {
         let (tx, rx) = mpsc::unbounded_channel::<IndexCommand<T>>();
         let map = HashMap::new();
         let rwlock = RwLock::new(map);
         let arc = Arc::new(rwlock);
 
-        Self::spawn_receiver(rx, arc.clone(), cnfg);
+        Self::spawn_receiver(rx, arc.clone(), cnfg.clone());
 
         Self {
             cmd_chan: tx,
             catalog: arc,
+            app_conf: cnfg,
+        }
+    }
+
+    /// every index under the index path, sorted by name, with whether it is currently open
+    pub fn list_indexes(&self) -> io::Result<Vec<(String, bool)>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.app_conf.index_path)? {
+            let path = entry?.path();
+            //anything without tantivy's meta.json is not an index
+            if !path.join("meta.json").is_file() {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                names.push(name.to_string());
+            }
         }
+        names.sort();
+        let catalog = self.catalog.read().unwrap();
+        Ok(names.into_iter().map(|n| {
+            let open = catalog.contains_key(&n);
+            (n, open)
+        }).collect())
+    }
+
+    /// the mapping of an index. a closed index stays closed, its schema is read from tantivy's
+    /// meta.json and its settings from the settings file.
+    pub fn mapping(&self, name: &str) -> Result<IndexConfig, NimoolError> {
+        if let Some(idx) = self.catalog.read().unwrap().get(name) {
+            return Ok(IndexConfig::from_schema(name, idx.get_schema(), idx.get_settings()));
+        }
+        let dir = self.app_conf.index_path.join(name);
+        let meta = match fs::read(dir.join("meta.json")) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(NimoolError::for_index(name, TantivyError::PathDoesNotExist(dir))),
+            meta => meta?
+        };
+        //read straight from the bytes, tantivy's field entries have to keep the order of their keys
+        let meta: IndexMeta = serde_json::from_slice(&meta)?;
+        let settings = IndexSettings::load(&dir).map_err(|e| NimoolError::for_index(name, e))?;
+        Ok(IndexConfig::from_schema(name, &meta.schema, settings))
     }
 
-    pub fn get_index_handle(&self, name: &str) -> impl Future<Item=TantivyResul<IndexDescriptor>, Error=NimoolError> {
+    pub fn get_index_handle(&self, name: &str) -> impl Future<Item=IndexDescriptor, Error=NimoolError> {
         let catalog = self.catalog.read().unwrap();
         info!("trying to find index handle in cache: {}", name);
         if catalog.contains_key(name) {
             info!("index in cache : {}", name);
             let handle = catalog.get(name).unwrap();
-            return Either::A(future::ok(Ok(handle.clone())));
+            return Either::A(future::ok(handle.clone()));
         }
         info!("index not in cache. requesting from catalog maintainer task. index name : {}", name);
         let (tx, rx) = oneshot::channel();
@@ -82,26 +131,81 @@ impl<T> IndexCatalog<T> where T: 'static + Into<TFieldType> + Debug + Send {
         //important. if we don't drop the reader lock here and just wait for the worker thread on the receiving end, the worker thread
         // can never acquire the writer lock, thus there will be a dead lock in other words there will be BLOOD!
         drop(catalog);
-        let f = self.cmd_chan.clone().send(open_cmd).map_err(|e| {
-            NimoolError::from(e)
-        }).and_then(move |_| {
-            rx.map_err(|e| {
-                NimoolError::from(e)
-            })
-        });
-        Either::B(f)
+        Either::B(self.send_command(name, open_cmd, rx))
+    }
+
+    /// sends a command to the catalog task and waits for its reply. errors of the command are
+    /// reported as errors of the index `name`.
+    fn send_command<R>(&self, name: &str, cmd: IndexCommand<T>, reply: oneshot::Receiver<TantivyResul<R>>) -> impl Future<Item=R, Error=NimoolError> {
+        let name = name.to_string();
+        self.cmd_chan.clone().send(cmd).map_err(NimoolError::from)
+            .and_then(move |_| reply.map_err(NimoolError::from))
+            .and_then(move |res| res.map_err(|e| NimoolError::for_index(&name, e)))
     }
 
-    pub fn create_index(&self, creation_config: IndexCreationConfig<T>) -> impl Future<Item=TantivyResul<IndexDescriptor>, Error=NimoolError> {
+    pub fn create_index(&self, creation_config: IndexCreationConfig<T>) -> impl Future<Item=IndexDescriptor, Error=NimoolError> {
         let (tx, rx) = oneshot::channel();
+        let name = creation_config.index_name.clone();
         let cmd = IndexCommand::NCreate(NCreateIndexCmd {
             reply_on: tx,
             create_config: creation_config,
         });
-        self.cmd_chan.clone().send(cmd).map_err(|e| NimoolError::from(e))
-            .and_then(move |_| {
-                rx.map_err(|e| NimoolError::from(e))
-            })
+        self.send_command(&name, cmd, rx)
+    }
+
+    pub fn create(&self, index_config: IndexConfig) -> impl Future<Item=IndexDescriptor, Error=NimoolError> {
+        let (tx, rx) = oneshot::channel();
+        let name = index_config.index_name.clone();
+        let cmd = IndexCommand::Create(CreateIndexCmd::new(index_config, tx));
+        self.send_command(&name, cmd, rx)
+    }
+
+    pub fn close(&self, name: &str) -> impl Future<Item=(), Error=NimoolError> {
+        let (tx, rx) = oneshot::channel();
+        let cmd = IndexCommand::Close(CloseIndexCmd::new(name, tx));
+        self.send_command(name, cmd, rx)
+    }
+
+    pub fn delete(&self, name: &str) -> impl Future<Item=(), Error=NimoolError> {
+        let (tx, rx) = oneshot::channel();
+        let cmd = IndexCommand::Delete(CloseIndexCmd::new(name, tx));
+        self.send_command(name, cmd, rx)
+    }
+
+    /// flushes and closes every open index. dropping the descriptors releases the writer locks, so
+    /// nothing else should hold on to one when this is called.
+    pub fn shutdown(&self) -> impl Future<Item=Vec<(String, u64)>, Error=NimoolError> {
+        let (tx, rx) = oneshot::channel();
+        self.send_command("", IndexCommand::Shutdown(tx), rx)
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use futures::future::Future;
+    use serde_json::json;
+    use crate::DummyIntoFieldType;
+    use crate::db::testing::{with_app_conf, index_config};
+    use super::IndexCatalog;
+
+    #[test]
+    fn test_mapping_of_a_closed_index() {
+        let (mapping, indexes) = with_app_conf(|app_conf| {
+            let catalog: IndexCatalog<DummyIntoFieldType> = IndexCatalog::new(Arc::new(app_conf));
+            let config = index_config(json!({ "index_name": "books", "fields": [
+                { "name": "title", "field_type": { "Text": { "index_options": { "analyzer": { "Analyzed": "default" }, "record_options": "Freq", "search_analyzer": "raw" }, "store": true } } }
+            ] }));
+            let closed = catalog.clone();
+            catalog.create(config)
+                .and_then(move |_| closed.close("books").map(move |_| closed))
+                .and_then(|catalog| Ok((catalog.mapping("books")?, catalog.list_indexes()?)))
+        }).unwrap();
+        //reading the mapping did not open the index
+        assert_eq!(indexes, vec![("books".to_string(), false)]);
+        let mapping = serde_json::to_value(&mapping).unwrap();
+        assert_eq!(mapping["fields"][0]["name"], "title");
+        assert_eq!(mapping["fields"][0]["field_type"]["Text"]["index_options"]["search_analyzer"], "raw");
+    }
+}
@@ -9,14 +9,15 @@ use super::config::{
     TextIndexConfig,
     IndexCreationConfig,
 };
-use tantivy::Result;
+use tantivy::{Result, TantivyError};
 use tantivy::schema::Schema;
-use std::sync::RwLock;
+use std::sync::{RwLock, Arc};
 use std::collections::HashMap;
 use crate::config::AppConf;
 use serde::export::fmt::Debug;
 use tantivy::schema::FieldType as TFieldType;
 use std::process::id;
+use std::fs;
 
 
 pub type ReplyOn<T> = tokio::sync::oneshot::Sender<Result<T>>;
@@ -24,7 +25,7 @@ pub type ShutdownHandle = tokio::sync::oneshot::Sender<()>;
 
 
 pub trait CmdHandler {
-    fn handle(self, app_conf: &'static AppConf, catalog: &RwLock<HashMap<String, IndexDescriptor>>) -> Option<ShutdownHandle>;
+    fn handle(self, app_conf: &AppConf, catalog: &RwLock<HashMap<String, IndexDescriptor>>, shutdown_handles: &mut HashMap<String, ShutdownHandle>);
 }
 
 pub struct OpenIndexCmd {
@@ -56,6 +57,21 @@ impl CreateIndexCmd {
     }
 }
 
+/// used for both close and delete, they only differ in what happens to the index directory
+pub struct CloseIndexCmd {
+    pub index_name: String,
+    pub reply_on: ReplyOn<()>,
+}
+
+impl CloseIndexCmd {
+    pub fn new(name: &str, s: ReplyOn<()>) -> Self {
+        Self {
+            index_name: name.to_string(),
+            reply_on: s,
+        }
+    }
+}
+
 pub struct NCreateIndexCmd<T> where T: Into<TFieldType> + Debug + Send {
     pub reply_on: ReplyOn<IndexDescriptor>,
     pub create_config: IndexCreationConfig<T>,
@@ -66,27 +82,48 @@ pub enum IndexCommand<T> where T: Into<TFieldType> + Debug + Send {
     Open(OpenIndexCmd),
     Create(CreateIndexCmd),
     NCreate(NCreateIndexCmd<T>),
+    Close(CloseIndexCmd),
+    Delete(CloseIndexCmd),
+    /// closes every open index, replies with the number of operations flushed per index
+    Shutdown(ReplyOn<Vec<(String, u64)>>),
+}
+
+/// commits pending writes, drops the writer, stops the maintainer task and evicts the index from
+/// the catalog. once it returns nothing holds the lock on the index directory anymore, descriptors
+/// still around fail to write. returns false when the index was not open.
+fn close_index(name: &str, cat: &mut HashMap<String, IndexDescriptor>, shutdown_handles: &mut HashMap<String, ShutdownHandle>) -> Result<bool> {
+    let idx = match cat.get(name) {
+        Some(idx) => idx,
+        None => return Ok(false)
+    };
+    idx.close()?;
+    cat.remove(name);
+    if let Some(h) = shutdown_handles.remove(name) {
+        //the maintainer task may already be gone, nothing to do then
+        let _ = h.send(());
+    }
+    info!("index closed : {}", name);
+    Ok(true)
 }
 
 impl<T> CmdHandler for IndexCommand<T> where T: Into<TFieldType> + Debug + Send {
-    fn handle(self, app_conf: &'static AppConf, catalog: &RwLock<HashMap<String, IndexDescriptor>>) -> Option<ShutdownHandle> {
+    fn handle(self, app_conf: &AppConf, catalog: &RwLock<HashMap<String, IndexDescriptor>>, shutdown_handles: &mut HashMap<String, ShutdownHandle>) {
+        //the requester may have gone away before the reply, it is dropped then
         match self {
             IndexCommand::Open(o) => {
                 let mut cat = catalog.write().unwrap();
                 if cat.contains_key(&o.index_name) {
-                    o.reply_on.send(Ok(cat.get(&o.index_name).unwrap().clone()));
-                    return None;
+                    let _ = o.reply_on.send(Ok(cat.get(&o.index_name).unwrap().clone()));
                 } else {
                     let open_result = IndexDescriptor::open(app_conf, &o.index_name);
                     match open_result {
                         Ok(idx) => {
-                            cat.insert(o.index_name, idx.descriptor.clone());
-                            o.reply_on.send(Ok(idx.descriptor));
-                            return Some(idx.shut_down_handle);
+                            cat.insert(o.index_name.clone(), idx.descriptor.clone());
+                            shutdown_handles.insert(o.index_name, idx.shut_down_handle);
+                            let _ = o.reply_on.send(Ok(idx.descriptor));
                         }
                         Err(e) => {
-                            o.reply_on.send(Err(e));
-                            return None;
+                            let _ = o.reply_on.send(Err(e));
                         }
                     };
                 }
@@ -95,20 +132,18 @@ impl<T> CmdHandler for IndexCommand<T> where T: Into<TFieldType> + Debug + Send
             IndexCommand::Create(c) => {
                 let mut cat = catalog.write().unwrap();
                 if cat.contains_key(&c.index_config.index_name) {
-                    let idx = cat.get(&c.index_config.index_name).unwrap().clone();
-                    c.reply_on.send(Ok(idx));
-                    return None;
+                    let _ = c.reply_on.send(Err(TantivyError::IndexAlreadyExists));
                 } else {
-                    let create_result = IndexDescriptor::create(app_conf, c.index_config.fields, &c.index_config.index_name);
+                    let settings = c.index_config.effective_settings();
+                    let create_result = IndexDescriptor::create(app_conf, c.index_config.fields, settings, &c.index_config.index_name);
                     match create_result {
                         Ok(idx) => {
-                            cat.insert(c.index_config.index_name, idx.descriptor.clone());
-                            c.reply_on.send(Ok(idx.descriptor));
-                            return Some(idx.shut_down_handle);
+                            cat.insert(c.index_config.index_name.clone(), idx.descriptor.clone());
+                            shutdown_handles.insert(c.index_config.index_name, idx.shut_down_handle);
+                            let _ = c.reply_on.send(Ok(idx.descriptor));
                         }
                         Err(e) => {
-                            c.reply_on.send(Err(e));
-                            return None;
+                            let _ = c.reply_on.send(Err(e));
                         }
                     }
                 }
@@ -117,22 +152,60 @@ impl<T> CmdHandler for IndexCommand<T> where T: Into<TFieldType> + Debug + Send
                 let mut cat = catalog.write().unwrap();
                 if cat.contains_key(&c.create_config.index_name) {
                     let idx = cat.get(&c.create_config.index_name).unwrap().clone();
-                    c.reply_on.send(Ok(idx));
-                    return None;
+                    let _ = c.reply_on.send(Ok(idx));
                 } else {
                     match IndexDescriptor::n_create(app_conf, c.create_config.fields, &c.create_config.index_name) {
                         Ok(idx) => {
-                            cat.insert(c.create_config.index_name, idx.descriptor.clone());
-                            c.reply_on.send(Ok(idx.descriptor));
-                            return Some(idx.shut_down_handle);
+                            cat.insert(c.create_config.index_name.clone(), idx.descriptor.clone());
+                            shutdown_handles.insert(c.create_config.index_name, idx.shut_down_handle);
+                            let _ = c.reply_on.send(Ok(idx.descriptor));
                         }
                         Err(e) => {
-                            c.reply_on.send(Err(e));
-                            return None;
+                            let _ = c.reply_on.send(Err(e));
                         }
                     }
                 }
             }
+            IndexCommand::Close(c) => {
+                let mut cat = catalog.write().unwrap();
+                let path = app_conf.index_path.join(&c.index_name);
+                let res = close_index(&c.index_name, &mut cat, shutdown_handles).and_then(|was_open| {
+                    if was_open || path.exists() {
+                        Ok(())
+                    } else {
+                        Err(TantivyError::PathDoesNotExist(path))
+                    }
+                });
+                let _ = c.reply_on.send(res);
+            }
+            IndexCommand::Delete(c) => {
+                let mut cat = catalog.write().unwrap();
+                let path = app_conf.index_path.join(&c.index_name);
+                let res = close_index(&c.index_name, &mut cat, shutdown_handles).and_then(|_| {
+                    if path.exists() {
+                        info!("deleting index : {}", c.index_name);
+                        fs::remove_dir_all(&path).map_err(TantivyError::from)
+                    } else {
+                        Err(TantivyError::PathDoesNotExist(path))
+                    }
+                });
+                let _ = c.reply_on.send(res);
+            }
+            IndexCommand::Shutdown(reply_on) => {
+                let mut cat = catalog.write().unwrap();
+                let mut names: Vec<String> = cat.keys().cloned().collect();
+                names.sort();
+                let mut flushed = Vec::with_capacity(names.len());
+                for name in names {
+                    let pending = cat.get(&name).map_or(0, |idx| idx.pending_count());
+                    match close_index(&name, &mut cat, shutdown_handles) {
+                        Ok(_) => flushed.push((name, pending)),
+                        //keep going, the other indexes still deserve their commit
+                        Err(e) => error!("could not flush index {} on shutdown: {:?}", name, e)
+                    }
+                }
+                let _ = reply_on.send(Ok(flushed));
+            }
         };
     }
 }
@@ -149,30 +222,125 @@ impl<T> Debug for IndexCommand<T> where T: Into<TFieldType> + Debug + Send {
             IndexCommand::NCreate(ref c) => {
                 write!(f, "create command for index: {:?}", c.create_config.index_name)
             }
+            IndexCommand::Close(ref c) => {
+                write!(f, "close command for index: {:?}", c.index_name)
+            }
+            IndexCommand::Delete(ref c) => {
+                write!(f, "delete command for index: {:?}", c.index_name)
+            }
+            IndexCommand::Shutdown(_) => {
+                write!(f, "shutdown command")
+            }
         }
     }
 }
 
 #[derive(Clone)]
 pub struct IndexCommandHandler {
-    app_conf: &'static AppConf
+    app_conf: Arc<AppConf>
 }
 
 impl IndexCommandHandler {
-    pub fn new(conf: &'static AppConf) -> Self {
+    pub fn new(conf: Arc<AppConf>) -> Self {
         Self {
             app_conf: conf
         }
     }
 
 
-    pub fn handle_command<C>(&self, cmd: C, catalog: &RwLock<HashMap<String, IndexDescriptor>>) -> Option<ShutdownHandle> where C: CmdHandler {
-        cmd.handle(self.app_conf, catalog)
+    pub fn handle_command<C>(&self, cmd: C, catalog: &RwLock<HashMap<String, IndexDescriptor>>, shutdown_handles: &mut HashMap<String, ShutdownHandle>) where C: CmdHandler {
+        cmd.handle(&self.app_conf, catalog, shutdown_handles)
     }
 }
 
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::RwLock;
+    use futures::future::Future;
+    use serde_json::json;
+    use tokio::sync::oneshot;
+    use crate::DummyIntoFieldType;
+    use crate::db::testing::{with_app_conf, index_config};
+    use super::{IndexCommand, CmdHandler, CreateIndexCmd, OpenIndexCmd, CloseIndexCmd};
+
+    #[test]
+    fn test_close_and_delete_release_the_index() {
+        with_app_conf(|app_conf| {
+            let dir = app_conf.index_path.clone();
+            let catalog = RwLock::new(HashMap::new());
+            let mut handles = HashMap::new();
+            let mut run = |cmd: IndexCommand<DummyIntoFieldType>| cmd.handle(&app_conf, &catalog, &mut handles);
+            let create = |run: &mut dyn FnMut(IndexCommand<DummyIntoFieldType>)| {
+                let config = index_config(json!({ "index_name": "books", "fields": [] }));
+                let (tx, rx) = oneshot::channel();
+                run(IndexCommand::Create(CreateIndexCmd::new(config, tx)));
+                rx.wait().unwrap().unwrap()
+            };
+            let close = |run: &mut dyn FnMut(IndexCommand<DummyIntoFieldType>), delete: bool| {
+                let (tx, rx) = oneshot::channel();
+                let cmd = CloseIndexCmd::new("books", tx);
+                run(if delete { IndexCommand::Delete(cmd) } else { IndexCommand::Close(cmd) });
+                rx.wait().unwrap()
+            };
+
+            let idx = create(&mut run);
+            idx.add_parsed_documents(vec![idx.parse_document(r#"{"_id": "1"}"#, None).unwrap()]).unwrap();
+            close(&mut run, false).unwrap();
+            //descriptors handed out before the close no longer hold the writer
+            assert!(idx.commit().is_err());
+
+            let (tx, rx) = oneshot::channel();
+            run(IndexCommand::Open(OpenIndexCmd::new("books", tx)));
+            let reopened = rx.wait().unwrap().unwrap();
+            assert!(reopened.get_by_id("1").unwrap().is_some());
 
+            close(&mut run, true).unwrap();
+            assert!(!dir.join("books").exists());
+            assert!(close(&mut run, true).is_err());
 
+            let recreated = create(&mut run);
+            assert!(recreated.get_by_id("1").unwrap().is_none());
+            recreated.add_parsed_documents(vec![recreated.parse_document(r#"{"_id": "2"}"#, None).unwrap()]).unwrap();
+            //the late drop of an old descriptor must not touch the new index
+            drop(idx);
+            drop(reopened);
+            assert!(recreated.get_by_id("2").unwrap().is_some());
+            Ok::<_, ()>(())
+        }).unwrap();
+    }
 
+    #[test]
+    fn test_shutdown_flushes_and_releases_every_index() {
+        with_app_conf(|app_conf| {
+            let dir = app_conf.index_path.clone();
+            let catalog = RwLock::new(HashMap::new());
+            let mut handles = HashMap::new();
+            let mut run = |cmd: IndexCommand<DummyIntoFieldType>| cmd.handle(&app_conf, &catalog, &mut handles);
+            let mut indexes = Vec::new();
+            for name in &["books", "authors"] {
+                let (tx, rx) = oneshot::channel();
+                run(IndexCommand::Create(CreateIndexCmd::new(index_config(json!({ "index_name": name, "fields": [] })), tx)));
+                indexes.push(rx.wait().unwrap().unwrap());
+            }
+            let books = &indexes[0];
+            let docs = vec![books.parse_document(r#"{"_id": "1"}"#, None).unwrap(), books.parse_document(r#"{"_id": "2"}"#, None).unwrap()];
+            books.add_parsed_documents(docs).unwrap();
 
+            let (tx, rx) = oneshot::channel();
+            run(IndexCommand::Shutdown(tx));
+            assert_eq!(rx.wait().unwrap().unwrap(), vec![("authors".to_string(), 0), ("books".to_string(), 2)]);
+            assert!(books.commit().is_err());
 
+            //the documents are in a commit, not only in the translog
+            let committed = tantivy::Index::open_in_dir(dir.join("books")).unwrap();
+            assert_eq!(committed.reader().unwrap().searcher().num_docs(), 2);
+            let (tx, rx) = oneshot::channel();
+            run(IndexCommand::Open(OpenIndexCmd::new("books", tx)));
+            let reopened = rx.wait().unwrap().unwrap();
+            assert!(reopened.get_by_id("2").unwrap().is_some());
+            assert!(reopened.commit().is_ok());
+            Ok::<_, ()>(())
+        }).unwrap();
+    }
+}
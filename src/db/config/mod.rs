@@ -1,9 +1,17 @@
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
-use tantivy::schema::{STORED, TEXT, TextOptions, IndexRecordOption, TextFieldIndexing, IntOptions};
+use std::collections::{HashMap, BTreeMap};
+use tantivy::schema::{STORED, TEXT, TextOptions, IndexRecordOption, TextFieldIndexing, IntOptions, Cardinality, is_valid_field_name};
+use crate::db::analysis::{self, AnalyzerDef, SynonymMap};
 use core::borrow::Borrow;
 use tantivy::schema::FieldType as TFiledType;
+use tantivy::schema::Schema;
 use serde::export::fmt::Debug;
+use super::error::FieldMappingError;
+use std::path::Path;
+use std::fs;
+use tantivy::Result as TantivyResult;
+use tantivy::TantivyError;
+use serde_json::{Map, Value};
 
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, )]
@@ -14,6 +22,16 @@ pub enum FieldTermOption {
 }
 
 
+impl From<IndexRecordOption> for FieldTermOption {
+    fn from(opt: IndexRecordOption) -> Self {
+        match opt {
+            IndexRecordOption::Basic => FieldTermOption::DocId,
+            IndexRecordOption::WithFreqs => FieldTermOption::Freq,
+            IndexRecordOption::WithFreqsAndPositions => FieldTermOption::FreqPos
+        }
+    }
+}
+
 impl Into<IndexRecordOption> for FieldTermOption {
     fn into(self) -> IndexRecordOption {
         match self {
@@ -44,6 +62,10 @@ impl AnalyzeOption {
 pub struct IndexOpt {
     pub analyzer: AnalyzeOption,
     pub record_options: FieldTermOption,
+    /// analyzer for query text, when it should differ from the one documents go through. it is kept
+    /// in the index settings, see `IndexSettings::search_analyzers`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search_analyzer: Option<String>,
 }
 
 impl IndexOpt {
@@ -51,6 +73,7 @@ impl IndexOpt {
         Self {
             analyzer: AnalyzeOption::new_analyzed(analyzer),
             record_options: FieldTermOption::Freq,
+            search_analyzer: None,
         }
     }
 
@@ -61,6 +84,7 @@ impl IndexOpt {
                 None => AnalyzeOption::Keyword
             },
             record_options: rec_opts,
+            search_analyzer: None,
         }
     }
 }
@@ -76,6 +100,7 @@ impl TextIndexConfig {
         let opts = IndexOpt {
             analyzer: AnalyzeOption::new_kw(),
             record_options: FieldTermOption::DocId,
+            search_analyzer: None,
         };
 
         Self {
@@ -128,6 +153,9 @@ impl Into<TextOptions> for TextIndexConfig {
 pub struct NumberIndexConfig {
     pub stored: bool,
     pub indexed: bool,
+    /// keeps a column of the values next to the index, needed to sort on the field
+    #[serde(default)]
+    pub fast: bool,
 }
 
 impl NumberIndexConfig {
@@ -135,8 +163,14 @@ impl NumberIndexConfig {
         Self {
             stored,
             indexed,
+            fast: false,
         }
     }
+
+    pub fn with_fast(mut self) -> Self {
+        self.fast = true;
+        self
+    }
 }
 
 impl Into<IntOptions> for NumberIndexConfig {
@@ -148,6 +182,9 @@ impl Into<IntOptions> for NumberIndexConfig {
         if self.indexed {
             result = result.set_indexed();
         }
+        if self.fast {
+            result = result.set_fast(Cardinality::SingleValue);
+        }
         result
     }
 }
@@ -161,6 +198,35 @@ pub enum FieldType {
     Date(NumberIndexConfig),
 }
 
+impl FieldType {
+    /// maps a tantivy field type back onto the mapping it was created from. bytes and facet fields
+    /// can not be created through a mapping and have no counterpart.
+    pub fn from_tantivy(ft: &TFiledType) -> Option<Self> {
+        let number = |opts: &IntOptions| NumberIndexConfig {
+            stored: opts.is_stored(),
+            indexed: opts.is_indexed(),
+            fast: opts.is_fast(),
+        };
+        match ft {
+            TFiledType::Str(opts) => Some(FieldType::Text(TextIndexConfig {
+                index_options: opts.get_indexing_options().map(|idx| IndexOpt {
+                    analyzer: match idx.tokenizer() {
+                        "raw" => AnalyzeOption::Keyword,
+                        an => AnalyzeOption::new_analyzed(an)
+                    },
+                    record_options: idx.index_option().into(),
+                    search_analyzer: None,
+                }),
+                store: opts.is_stored(),
+            })),
+            TFiledType::U64(opts) => Some(FieldType::UInt64(number(opts))),
+            TFiledType::I64(opts) => Some(FieldType::Int64(number(opts))),
+            TFiledType::Date(opts) => Some(FieldType::Date(number(opts))),
+            TFiledType::Bytes | TFiledType::HierarchicalFacet => None
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Field {
     pub name: String,
@@ -177,10 +243,199 @@ impl Field {
 }
 
 
+pub const SETTINGS_FILE: &str = "settings.json";
+
+/// reserved keyword field every index gets, documents are fetched, replaced and deleted by it
+pub const ID_FIELD: &str = "_id";
+
+//tantivy does not write fast columns for date fields yet. a fast date field gets a hidden i64 fast
+//field next to it instead, holding the timestamp of the date
+const DATE_SORT_PREFIX: &str = "_sort_";
+
+/// name of the i64 fast field that holds the timestamps of the fast date field `field`
+pub fn date_sort_field(field: &str) -> String {
+    format!("{}{}", DATE_SORT_PREFIX, field)
+}
+
+fn default_max_result_window() -> usize {
+    10_000
+}
+
+//tantivy needs at least this much writer memory per indexing thread and refuses 4GB or more, nimool
+//uses at most 8 threads
+pub(crate) const MIN_HEAP_PER_THREAD: usize = 3_000_000;
+const MAX_HEAP_PER_THREAD: usize = u32::max_value() as usize - 1_000_000;
+pub(crate) const MAX_INDEXING_THREADS: usize = 8;
+
+/// when writes are fsynced to the translog. `request` syncs before a write is acknowledged, so an
+/// acknowledged write survives a crash of the machine. `async` acknowledges a write once it is
+/// handed to the os: it survives a crash of the process, but the writes the os has not flushed yet
+/// are lost on a power failure or a crash of the os. a commit always syncs what it made durable.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    Request,
+    Async,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::Request
+    }
+}
+
+/// per index settings. they live in the index directory next to tantivy's meta.json. unset
+/// options fall back to the server configuration.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IndexSettings {
+    /// upper bound for `from + size` of a search request
+    #[serde(default = "default_max_result_window")]
+    pub max_result_window: usize,
+    /// memory budget of the index writer in bytes, shared by all indexing threads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub writer_heap_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indexing_threads: Option<usize>,
+    /// seconds between two runs of the auto commit task
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_commit_interval: Option<u64>,
+    /// commit as soon as this many operations are pending instead of waiting for the auto commit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_every_docs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub translog_durability: Option<Durability>,
+    /// custom analyzers by name, fields refer to them like to the built-in ones
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub analyzers: BTreeMap<String, AnalyzerDef>,
+    /// analyzers for query text by field name. unlike the index analyzers they can be changed at
+    /// any time, documents do not depend on them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub search_analyzers: BTreeMap<String, String>,
+    /// synonym rules by set name, for the synonym filters of the analyzers
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub synonyms: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for IndexSettings {
+    fn default() -> Self {
+        Self {
+            max_result_window: default_max_result_window(),
+            writer_heap_size: None,
+            indexing_threads: None,
+            auto_commit_interval: None,
+            commit_every_docs: None,
+            translog_durability: None,
+            analyzers: BTreeMap::new(),
+            search_analyzers: BTreeMap::new(),
+            synonyms: BTreeMap::new(),
+        }
+    }
+}
+
+impl IndexSettings {
+    /// indexes created before settings existed have no settings file, they get the defaults
+    pub fn load(index_dir: &Path) -> TantivyResult<Self> {
+        let path = index_dir.join(SETTINGS_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.max_result_window == 0 {
+            errors.push("max_result_window must be greater than zero".to_string());
+        }
+        if let Some(threads) = self.indexing_threads {
+            if threads == 0 || threads > MAX_INDEXING_THREADS {
+                errors.push(format!("indexing_threads must be between 1 and {}", MAX_INDEXING_THREADS));
+            }
+        }
+        if self.auto_commit_interval == Some(0) {
+            errors.push("auto_commit_interval must be at least one second".to_string());
+        }
+        if self.commit_every_docs == Some(0) {
+            errors.push("commit_every_docs must be greater than zero".to_string());
+        }
+        for (name, def) in &self.analyzers {
+            errors.extend(def.validate(name));
+        }
+        let tokenizers = analysis::tokenizers_with(&self.analyzers);
+        for (field, an) in &self.search_analyzers {
+            if tokenizers.get(an).is_none() {
+                errors.push(format!("unknown search analyzer for field {}: {}", field, an));
+            }
+        }
+        for (name, rules) in &self.synonyms {
+            if let Err(e) = SynonymMap::parse(rules) {
+                errors.push(format!("synonym set {}: {}", name, e));
+            }
+        }
+        errors
+    }
+
+    /// the number of indexing threads and the heap they share, `default_heap` stands in for an
+    /// unset `writer_heap_size`. without `indexing_threads` there is a thread per cpu, as many as
+    /// the heap has room for.
+    pub fn writer_threads_and_heap(&self, default_heap: usize) -> (usize, usize) {
+        let heap = self.writer_heap_size.unwrap_or(default_heap);
+        let threads = self.indexing_threads.unwrap_or_else(|| {
+            num_cpus::get().min(MAX_INDEXING_THREADS).min(heap / MIN_HEAP_PER_THREAD).max(1)
+        });
+        (threads, heap)
+    }
+
+    /// checks the heap every indexing thread ends up with against the bounds of tantivy
+    pub fn validate_writer(&self, default_heap: usize) -> Result<(), String> {
+        let (threads, heap) = self.writer_threads_and_heap(default_heap);
+        let per_thread = heap / threads.max(1);
+        if per_thread < MIN_HEAP_PER_THREAD || per_thread >= MAX_HEAP_PER_THREAD {
+            return Err(format!("writer heap of {} bytes for {} indexing threads is out of bounds, every thread needs at least {} and less than {} bytes",
+                               heap, threads, MIN_HEAP_PER_THREAD, MAX_HEAP_PER_THREAD));
+        }
+        Ok(())
+    }
+
+    /// whether going from `self` to `other` needs a new index writer
+    pub fn needs_new_writer(&self, other: &Self) -> bool {
+        self.writer_heap_size != other.writer_heap_size || self.indexing_threads != other.indexing_threads
+    }
+
+    /// the settings with the keys of `update` replaced, a `null` resets a setting to its default
+    pub fn merged(&self, update: Map<String, Value>) -> TantivyResult<Self> {
+        let mut merged = match serde_json::to_value(self)? {
+            Value::Object(map) => map,
+            _ => Map::new()
+        };
+        for (k, v) in update {
+            //a missing key takes the default, settings that are not optional do not take a null
+            if v.is_null() {
+                merged.remove(&k);
+            } else {
+                merged.insert(k, v);
+            }
+        }
+        serde_json::from_value(Value::Object(merged)).map_err(|e| TantivyError::InvalidArgument(e.to_string()))
+    }
+
+    pub fn store(&self, index_dir: &Path) -> TantivyResult<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(index_dir.join(SETTINGS_FILE), content)?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IndexConfig {
     pub fields: Vec<Field>,
+    //when the config comes in over http the name is taken from the path
+    #[serde(default)]
     pub index_name: String,
+    #[serde(default)]
+    pub settings: IndexSettings,
 }
 
 impl IndexConfig {
@@ -188,12 +443,105 @@ impl IndexConfig {
         Self {
             fields: Vec::new(),
             index_name: name.to_string(),
+            settings: IndexSettings::default(),
         }
     }
 
     pub fn add_field(&mut self, f: Field) {
         self.fields.push(f);
     }
+
+    /// the mapping of an existing index, in the shape the create endpoint takes. the reserved `_id`
+    /// field is left out since every index gets it anyway.
+    pub fn from_schema(name: &str, schema: &Schema, settings: IndexSettings) -> Self {
+        let fields = schema.fields().iter()
+            .filter(|e| e.name() != ID_FIELD && !e.name().starts_with(DATE_SORT_PREFIX))
+            .filter_map(|e| FieldType::from_tantivy(e.field_type()).map(|ft| Field::new(e.name(), ft)))
+            .map(|mut f: Field| {
+                match f.field_type {
+                    FieldType::Text(TextIndexConfig { index_options: Some(ref mut opts), .. }) => {
+                        opts.search_analyzer = settings.search_analyzers.get(&f.name).cloned();
+                    }
+                    FieldType::Date(ref mut c) => c.fast = schema.get_field(&date_sort_field(&f.name)).is_some(),
+                    _ => {}
+                }
+                f
+            })
+            .collect();
+        Self {
+            fields,
+            index_name: name.to_string(),
+            settings,
+        }
+    }
+
+    /// the settings to store with the index. search analyzers given in the mapping are moved into
+    /// them, the tantivy schema has no place for them.
+    pub fn effective_settings(&self) -> IndexSettings {
+        let mut settings = self.settings.clone();
+        for f in &self.fields {
+            if let FieldType::Text(TextIndexConfig { index_options: Some(IndexOpt { search_analyzer: Some(ref an), .. }), .. }) = f.field_type {
+                settings.search_analyzers.insert(f.name.clone(), an.clone());
+            }
+        }
+        settings
+    }
+
+    /// checks the mapping before it is handed to tantivy. tantivy happily builds a schema with
+    /// duplicate or unusable fields, so everything that is wrong is reported here, field by field.
+    pub fn validate(&self) -> Vec<FieldMappingError> {
+        let mut errors = Vec::new();
+        if self.fields.is_empty() {
+            errors.push(FieldMappingError::new("", "index must define at least one field"));
+        }
+        for e in self.settings.validate() {
+            errors.push(FieldMappingError::new("", &e));
+        }
+        let tokenizers = analysis::tokenizers_with(&self.settings.analyzers);
+        let mut seen = HashMap::new();
+        for f in &self.fields {
+            if f.name == ID_FIELD {
+                errors.push(FieldMappingError::new(&f.name, "_id is reserved, it is added to every index"));
+            } else if !is_valid_field_name(&f.name) {
+                errors.push(FieldMappingError::new(&f.name, "field name must start with a letter and contain only letters, digits and '_'"));
+            }
+            if seen.insert(f.name.as_str(), ()).is_some() {
+                errors.push(FieldMappingError::new(&f.name, "duplicate field name"));
+            }
+            match f.field_type {
+                FieldType::Text(ref c) => {
+                    if c.index_options.is_none() && !c.store {
+                        errors.push(FieldMappingError::new(&f.name, "text field is neither indexed nor stored"));
+                    }
+                    if let Some(IndexOpt { analyzer: AnalyzeOption::Analyzed(ref an), .. }) = c.index_options {
+                        if tokenizers.get(an).is_none() {
+                            errors.push(FieldMappingError::new(&f.name, &format!("unknown analyzer: {}", an)));
+                        }
+                    }
+                    if let Some(IndexOpt { search_analyzer: Some(ref an), .. }) = c.index_options {
+                        if tokenizers.get(an).is_none() {
+                            errors.push(FieldMappingError::new(&f.name, &format!("unknown search analyzer: {}", an)));
+                        }
+                    }
+                }
+                FieldType::Int64(c) | FieldType::UInt64(c) | FieldType::Date(c) => {
+                    if !c.indexed && !c.stored && !c.fast {
+                        errors.push(FieldMappingError::new(&f.name, "numeric field is neither indexed, stored nor fast"));
+                    }
+                }
+            }
+        }
+        for field in self.settings.search_analyzers.keys() {
+            let indexed_text = self.fields.iter().any(|f| match f.field_type {
+                FieldType::Text(TextIndexConfig { index_options: Some(_), .. }) => &f.name == field,
+                _ => false
+            });
+            if !indexed_text {
+                errors.push(FieldMappingError::new(field, "search analyzers can only be set on indexed text fields"));
+            }
+        }
+        errors
+    }
 }
 
 #[derive(Debug)]
@@ -218,10 +566,88 @@ pub struct IndexCreationConfig<T> where T: Into<TFiledType> + Debug + Send {
 }
 
 
+#[cfg(test)]
+mod test {
+    use super::{IndexConfig, IndexSettings, Field, FieldType, TextIndexConfig, NumberIndexConfig};
+    use tantivy::schema::Schema;
+
+    #[test]
+    fn test_mapping_from_schema() {
+        let mut builder = Schema::builder();
+        builder.add_text_field("title", TextIndexConfig::from_options(true, Some("default"), super::FieldTermOption::FreqPos));
+        builder.add_text_field("tag", TextIndexConfig::not_analyzed());
+        builder.add_i64_field("price", NumberIndexConfig::new(false, true).with_fast());
+        builder.add_date_field("published", NumberIndexConfig::new(true, true));
+        builder.add_i64_field(&super::date_sort_field("published"), tantivy::schema::FAST);
+        let conf = IndexConfig::from_schema("test", &builder.build(), IndexSettings::default());
+        let json = serde_json::to_value(&conf.fields).unwrap();
+        assert_eq!(json[0]["field_type"]["Text"]["index_options"]["analyzer"]["Analyzed"], "default");
+        assert_eq!(json[1]["field_type"]["Text"]["index_options"]["analyzer"], "Keyword");
+        assert_eq!(json[2]["field_type"]["Int64"]["fast"], true);
+        //the sort field of a date is not part of the mapping, it makes the date fast
+        assert_eq!(json[3]["field_type"]["Date"]["fast"], true);
+        assert_eq!(conf.fields.len(), 4);
+        assert!(conf.validate().is_empty());
+    }
 
+    #[test]
+    fn test_validate_mapping() {
+        let mut conf = IndexConfig::new("test");
+        conf.add_field(Field::new("title", FieldType::Text(TextIndexConfig::from_options(true, Some("default"), super::FieldTermOption::Freq))));
+        conf.add_field(Field::new("year", FieldType::UInt64(NumberIndexConfig::new(true, true))));
+        assert!(conf.validate().is_empty());
+
+        conf.add_field(Field::new("title", FieldType::Text(TextIndexConfig::from_options(false, Some("no_such_analyzer"), super::FieldTermOption::Freq))));
+        conf.add_field(Field::new("0bad", FieldType::Int64(NumberIndexConfig::new(false, false))));
+        conf.add_field(Field::new("published", FieldType::Date(NumberIndexConfig::new(true, true).with_fast())));
+        let errors = conf.validate();
+        assert_eq!(errors.len(), 4);
+        assert_eq!(errors[0].field, "title");
+    }
 
+    #[test]
+    fn test_merge_settings() {
+        let update = |json: serde_json::Value| match json {
+            serde_json::Value::Object(map) => map,
+            _ => panic!("settings updates are objects")
+        };
+        let mut current = IndexSettings::default();
+        current.max_result_window = 500;
+        current.indexing_threads = Some(2);
+        current.commit_every_docs = Some(100);
+
+        let merged = current.merged(update(serde_json::json!({ "indexing_threads": 4, "search_analyzers": { "title": "raw" } }))).unwrap();
+        assert_eq!(merged.indexing_threads, Some(4));
+        assert_eq!(merged.search_analyzers.get("title").map(String::as_str), Some("raw"));
+        //keys that are not in the update are kept
+        assert_eq!(merged.max_result_window, 500);
+        assert_eq!(merged.commit_every_docs, Some(100));
+
+        //null resets a setting to its default, optional or not
+        let reset = merged.merged(update(serde_json::json!({ "indexing_threads": null, "max_result_window": null, "search_analyzers": null }))).unwrap();
+        assert_eq!(reset.indexing_threads, None);
+        assert_eq!(reset.max_result_window, IndexSettings::default().max_result_window);
+        assert!(reset.search_analyzers.is_empty());
+        assert_eq!(reset.commit_every_docs, Some(100));
+
+        assert!(current.merged(update(serde_json::json!({ "no_such_setting": 1 }))).is_err());
+        assert!(current.merged(update(serde_json::json!({ "indexing_threads": "many" }))).is_err());
+    }
 
-
-
-
-
+    #[test]
+    fn test_writer_heap_bounds() {
+        let mut settings = IndexSettings::default();
+        settings.indexing_threads = Some(8);
+        //the default heap of the server is shared by the threads as well
+        assert!(settings.validate_writer(3_000_000).is_err());
+        assert!(settings.validate_writer(24_000_000).is_ok());
+        settings.writer_heap_size = Some(8 * 4_300_000_000);
+        assert!(settings.validate_writer(24_000_000).is_err());
+
+        settings.indexing_threads = None;
+        settings.writer_heap_size = None;
+        assert_eq!(settings.writer_threads_and_heap(3_000_000), (1, 3_000_000));
+        assert!(settings.validate_writer(3_000_000).is_ok());
+        assert!(settings.validate_writer(1_000_000).is_err());
+    }
+}
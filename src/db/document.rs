@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use tantivy::Document;
 
 //#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AddDocConfig {
@@ -9,4 +10,24 @@ pub struct AddDocConfig {
 pub struct Doc<'a> {
     pub doc: &'a str,
     pub config: AddDocConfig,
-}
\ No newline at end of file
+}
+
+/// an accepted write. it is in the translog and the writer whether or not the commit that was
+/// asked for (or that the `commit_every_docs` threshold triggered) worked, a failed commit is
+/// retried by the next one.
+pub struct WriteResult {
+    /// the opstamp of the write, or of the commit when it was committed
+    pub opstamp: u64,
+    pub committed: bool,
+    pub commit_error: Option<String>,
+}
+
+/// a document ready to be written, with its `_id`. when the caller picked the id the document
+/// replaces whatever is stored under it, generated ids are always new.
+pub struct ParsedDoc {
+    pub doc: Document,
+    pub id: Option<String>,
+    pub replace: bool,
+    //the json the document was parsed from, for the translog
+    pub source: String,
+}
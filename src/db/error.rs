@@ -1,55 +1,198 @@
 use tantivy::TantivyError;
 use tantivy::schema::DocParsingError;
-use tokio::sync::oneshot::error::RecvError as OneChotRecvError;
-use tokio::sync::mpsc::error::{SendError, RecvError};
+use tokio::sync::oneshot::error::RecvError as OneShotRecvError;
+use tokio::sync::mpsc::error::UnboundedSendError;
 use std::error::Error;
+use std::io;
 use std::fmt::{Display, Formatter};
+use serde::Serialize;
 
 pub fn doc_parsing_err_to_string(e: DocParsingError) -> String {
     match e {
-        DocParsingError::NoSuchFieldInSchema(s) => s,
-        DocParsingError::NotJSON(s) => s,
+        DocParsingError::NoSuchFieldInSchema(s) => format!("no such field in schema: {}", s),
+        DocParsingError::NotJSON(s) => format!("document is not valid json: {}", s),
         DocParsingError::ValueError(s, v) => format!("{} {:?}", s, v)
     }
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldMappingError {
+    pub field: String,
+    pub reason: String,
+}
+
+impl FieldMappingError {
+    pub fn new(field: &str, reason: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
+pub enum QueryDslError {
+    UnknownField(String),
+    NotIndexed(String),
+    TypeMismatch { field: String, expected: &'static str, found: String },
+    QueryString(String),
+    Invalid(String),
+}
+
+impl Display for QueryDslError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use QueryDslError::*;
+        match self {
+            UnknownField(name) => write!(f, "unknown field: {}", name),
+            NotIndexed(name) => write!(f, "field is not indexed: {}", name),
+            TypeMismatch { field, expected, found } => write!(f, "field {} expects a {} value, got {}", field, expected, found),
+            QueryString(s) => write!(f, "could not parse query string: {}", s),
+            Invalid(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl Error for QueryDslError {}
+
+/// a tantivy error as a std error, tantivy only implements `failure::Fail` for its errors
+#[derive(Debug)]
+pub struct TantivyCause(pub TantivyError);
+
+impl Display for TantivyCause {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for TantivyCause {}
+
+/// everything that can go wrong while serving a request. errors of other libraries are kept as
+/// they are, the router turns each kind into a status code and a json error body.
+#[derive(Debug)]
 pub enum NimoolError {
-    ChannelSendErr(String),
-    ChannelReceiveError(String),
-    GeneralError(String),
+    IndexNotFound(String),
+    IndexAlreadyExists(String),
+    /// the mapping of a new index, with every problem found in it
+    SchemaParse(Vec<FieldMappingError>),
+    DocumentParse(String),
+    QueryParse(QueryDslError),
+    /// a request body that is not the json it should be
+    RequestParse(serde_json::Error),
+    InvalidArgument(String),
+    /// another writer, most likely another process, holds the lock of the index
+    WriterLocked(TantivyCause),
+    Io(io::Error),
+    Tantivy(TantivyCause),
+    /// the catalog task is gone, which only happens while shutting down
+    ChannelClosed(String),
+    /// a request body over the limit of its route, in bytes
+    BodyTooLarge(usize),
 }
 
 impl NimoolError {
-    pub fn unwrap(&self) -> &str {
-        use NimoolError::*;
-        match self {
-            GeneralError(s) => s,
-            ChannelReceiveError(s) => s,
-            ChannelSendErr(s) => s,
+    /// like `From<TantivyError>`, for errors of an operation on the index `name`
+    pub fn for_index(name: &str, e: TantivyError) -> Self {
+        match e {
+            TantivyError::PathDoesNotExist(_) => NimoolError::IndexNotFound(name.to_string()),
+            TantivyError::IndexAlreadyExists => NimoolError::IndexAlreadyExists(name.to_string()),
+            e => NimoolError::from(e)
         }
     }
 
-    pub fn from<E: Error>(e: E) -> NimoolError {
-        NimoolError::GeneralError(e.description().to_string())
+    /// the `type` of the error in responses
+    pub fn kind(&self) -> &'static str {
+        use NimoolError::*;
+        match self {
+            IndexNotFound(_) => "index_not_found",
+            IndexAlreadyExists(_) => "index_already_exists",
+            SchemaParse(_) => "schema_parse",
+            DocumentParse(_) => "document_parse",
+            QueryParse(_) => "query_parse",
+            RequestParse(_) => "request_parse",
+            InvalidArgument(_) => "invalid_argument",
+            WriterLocked(_) => "writer_locked",
+            Io(_) => "io",
+            Tantivy(_) => "tantivy",
+            ChannelClosed(_) => "channel_closed",
+            BodyTooLarge(_) => "body_too_large",
+        }
     }
 }
 
 impl Display for NimoolError {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.unwrap())
+        use NimoolError::*;
+        match self {
+            IndexNotFound(name) => write!(f, "no such index: {}", name),
+            IndexAlreadyExists(name) => write!(f, "index already exists: {}", name),
+            SchemaParse(errors) => {
+                let reasons: Vec<String> = errors.iter()
+                    .map(|e| if e.field.is_empty() { e.reason.clone() } else { format!("{}: {}", e.field, e.reason) })
+                    .collect();
+                write!(f, "{}", reasons.join(", "))
+            }
+            DocumentParse(s) => write!(f, "{}", s),
+            QueryParse(e) => write!(f, "{}", e),
+            RequestParse(e) => write!(f, "{}", e),
+            InvalidArgument(s) => write!(f, "{}", s),
+            WriterLocked(e) => write!(f, "index is locked by another writer: {}", e),
+            Io(e) => write!(f, "{}", e),
+            Tantivy(e) => write!(f, "{}", e),
+            ChannelClosed(s) => write!(f, "catalog is not available: {}", s),
+            BodyTooLarge(limit) => write!(f, "request body is larger than the limit of {} bytes", limit),
+        }
     }
 }
 
-/*impl<E> From<E> for NimoolError where E: Error {
-
-}*/
+impl Error for NimoolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NimoolError::QueryParse(e) => Some(e),
+            NimoolError::RequestParse(e) => Some(e),
+            NimoolError::Io(e) => Some(e),
+            NimoolError::WriterLocked(e) | NimoolError::Tantivy(e) => Some(e),
+            _ => None
+        }
+    }
+}
 
-impl Error for NimoolError {}
+impl From<TantivyError> for NimoolError {
+    fn from(e: TantivyError) -> Self {
+        match e {
+            //tantivy reports query parser and argument errors as invalid arguments
+            TantivyError::InvalidArgument(s) => NimoolError::InvalidArgument(s),
+            e @ TantivyError::LockFailure(..) => NimoolError::WriterLocked(TantivyCause(e)),
+            e => NimoolError::Tantivy(TantivyCause(e))
+        }
+    }
+}
 
+impl From<io::Error> for NimoolError {
+    fn from(e: io::Error) -> Self {
+        NimoolError::Io(e)
+    }
+}
 
-unsafe impl Send for NimoolError {}
+impl From<serde_json::Error> for NimoolError {
+    fn from(e: serde_json::Error) -> Self {
+        NimoolError::RequestParse(e)
+    }
+}
 
-unsafe impl Sync for NimoolError {}
+impl From<QueryDslError> for NimoolError {
+    fn from(e: QueryDslError) -> Self {
+        NimoolError::QueryParse(e)
+    }
+}
 
+impl From<UnboundedSendError> for NimoolError {
+    fn from(e: UnboundedSendError) -> Self {
+        NimoolError::ChannelClosed(format!("{:?}", e))
+    }
+}
 
+impl From<OneShotRecvError> for NimoolError {
+    fn from(e: OneShotRecvError) -> Self {
+        NimoolError::ChannelClosed(format!("{:?}", e))
+    }
+}
@@ -1,12 +1,13 @@
 use tantivy::{IndexReader, IndexWriter, Index, Result, Document, TantivyError, ReloadPolicy, DocAddress};
 use tantivy::schema::FieldType as TFieldType;
+use tantivy::tokenizer::Token;
 
 
-use std::sync::{Arc, LockResult, MutexGuard, PoisonError};
+use std::sync::{Arc, LockResult, MutexGuard, PoisonError, RwLock};
 
 use std::sync::Mutex;
 use std::fmt::Debug;
-use tantivy::schema::{DocParsingError, Schema, Field as TField};
+use tantivy::schema::{DocParsingError, Schema, Field as TField, Term, Value as TValue, STRING, STORED, FAST};
 use tantivy::collector::{
     TopDocs,
     Count,
@@ -14,8 +15,11 @@ use tantivy::collector::{
 
 use std::time::{Instant, Duration};
 use crate::config::AppConf;
-use crate::db::search::{SearchQuery, SearchResult, QueryHandler};
-use std::path::Path;
+use crate::db::search::{SearchQuery, SearchResult, QueryHandler, Pagination, DocAddresses};
+use std::fs;
+use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use crate::db::analysis::{self, SearchAnalyzers, SynonymSets};
 
 
 use super::config::{
@@ -25,37 +29,78 @@ use super::config::{
     NumberIndexConfig,
     TextIndexConfig,
     TantivyFiled,
+    IndexSettings,
+    ID_FIELD,
+    date_sort_field,
 };
 
 use super::document::{
     AddDocConfig,
     Doc,
+    ParsedDoc,
+    WriteResult,
 };
+use super::error::doc_parsing_err_to_string;
 
 use std::sync::atomic::{AtomicU64, Ordering};
-use tantivy::query::{RegexQuery, Query};
-use std::ops::Deref;
+use tantivy::query::{RegexQuery, Query, TermQuery};
+use tantivy::schema::IndexRecordOption;
+use serde_json::Value;
+use uuid::Uuid;
+use std::ops::{Deref, DerefMut};
 
 use tokio::sync::oneshot::{Receiver as OneShotReceiver, Sender as OneShotSender, self};
 use tokio::prelude::*;
-use tokio::timer::{Interval, Error};
+use tokio::timer::{Delay, Error};
 
-use futures::future::Either;
+use futures::future::{self, Either, Loop};
 
 use crate::db::util;
 
-type WriterPoisonErr<'a> = PoisonError<MutexGuard<'a, IndexWriter>>;
+mod translog;
+
+use self::translog::{Translog, TranslogEntry};
+
+type WriterPoisonErr<'a> = PoisonError<MutexGuard<'a, Option<IndexWriter>>>;
+
+/// the writer of an open index, locked for as long as the guard lives
+struct WriterGuard<'a>(MutexGuard<'a, Option<IndexWriter>>);
+
+impl<'a> Deref for WriterGuard<'a> {
+    type Target = IndexWriter;
+    fn deref(&self) -> &IndexWriter {
+        self.0.as_ref().expect("writer guard of a closed index")
+    }
+}
+
+impl<'a> DerefMut for WriterGuard<'a> {
+    fn deref_mut(&mut self) -> &mut IndexWriter {
+        self.0.as_mut().expect("writer guard of a closed index")
+    }
+}
 
 
 #[derive(Clone)]
 pub struct IndexDescriptor {
     reader: IndexReader,
-    writer: Arc<Mutex<IndexWriter>>,
+    //taken out when the index is closed, which releases the lock on the index directory
+    writer: Arc<Mutex<Option<IndexWriter>>>,
+    //writer heap of the server config, for indexes that do not set their own
+    default_heap: usize,
     schema: Schema,
     raw_fields: Vec<TField>,
+    settings: Arc<RwLock<IndexSettings>>,
+    dir: PathBuf,
     index: Index,
     uncommited_count: Arc<AtomicU64>,
-
+    //the stored fields of the documents written since the last commit by `_id`, none for deletes.
+    //realtime gets read them from here, they are only changed while holding the writer lock. a
+    //failed commit empties it, gets serve the last commit until one succeeds again.
+    uncommitted_docs: Arc<Mutex<HashMap<String, Option<Document>>>>,
+    //only used while holding the writer lock, which keeps the log in the order of the writes
+    translog: Arc<Mutex<Translog>>,
+    //the analyzers registered for the index hold on to the same sets
+    synonyms: SynonymSets,
 }
 
 pub struct IndexResult {
@@ -74,13 +119,16 @@ impl IndexResult {
 
 
 impl IndexDescriptor {
-    pub fn open(config: &'static AppConf, name: &str) -> Result<IndexResult> {
+    pub fn open(config: &AppConf, name: &str) -> Result<IndexResult> {
         debug!("opening index : {}", name);
 
-        let path = Path::new(config.index_path).join(name);
+        let path = config.index_path.join(name);
 
-        Index::open_in_dir(path).and_then(|idx| {
-            let writer = idx.writer(config.writer_buff_size)?;
+        Index::open_in_dir(&path).and_then(move |idx| {
+            let settings = IndexSettings::load(&path)?;
+            let synonyms = SynonymSets::new(&settings.synonyms);
+            analysis::register(idx.tokenizers(), &settings.analyzers, &synonyms);
+            let writer = open_writer(&idx, &settings, config.writer_buff_size)?;
             let reader = idx.reader_builder()
                 .reload_policy(ReloadPolicy::OnCommit)
                 .try_into()?;
@@ -94,22 +142,36 @@ impl IndexDescriptor {
                 reader,
                 schema,
                 raw_fields,
+                settings: Arc::new(RwLock::new(settings)),
+                translog: Arc::new(Mutex::new(Translog::open(&path)?)),
+                synonyms,
+                dir: path,
                 index: idx,
-                writer: Arc::new(Mutex::new(writer)),
+                writer: Arc::new(Mutex::new(Some(writer))),
+                default_heap: config.writer_buff_size,
                 uncommited_count: Arc::new(AtomicU64::new(0)),
+                uncommitted_docs: Arc::new(Mutex::new(HashMap::new())),
             };
 
+            res.replay_translog()?;
             res.spawn_maintainer_task(config.auto_commit_interval, rx);
             Ok(IndexResult::new(res, tx))
         })
     }
 
-    pub fn create(app_conf: &'static AppConf, fields: Vec<Field>, name: &str) -> Result<IndexResult> {
+    pub fn create(app_conf: &AppConf, fields: Vec<Field>, settings: IndexSettings, name: &str) -> Result<IndexResult> {
         info!("creating index : {}", name);
-        let path = Path::new(app_conf.index_path).join(name);
+        let path = app_conf.index_path.join(name);
+        let existed = path.exists();
+        fs::create_dir_all(&path)?;
         let schema = create_schema(fields);
-        Index::create_in_dir(path, schema.clone()).and_then(|idx| {
-            let writer = idx.writer(app_conf.writer_buff_size)?;
+        let dir = path.clone();
+        let created = Index::create_in_dir(&path, schema.clone()).and_then(move |idx| {
+            let path = dir;
+            settings.store(&path)?;
+            let synonyms = SynonymSets::new(&settings.synonyms);
+            analysis::register(idx.tokenizers(), &settings.analyzers, &synonyms);
+            let writer = open_writer(&idx, &settings, app_conf.writer_buff_size)?;
             let reader = idx.reader_builder()
                 .reload_policy(ReloadPolicy::OnCommit)
                 .try_into()?;
@@ -118,31 +180,54 @@ impl IndexDescriptor {
                 reader,
                 schema,
                 raw_fields: Vec::new(),
+                settings: Arc::new(RwLock::new(settings)),
+                translog: Arc::new(Mutex::new(Translog::open(&path)?)),
+                synonyms,
+                dir: path,
                 index: idx,
-                writer: Arc::new(Mutex::new(writer)),
+                writer: Arc::new(Mutex::new(Some(writer))),
+                default_heap: app_conf.writer_buff_size,
                 uncommited_count: Arc::new(AtomicU64::new(0)),
+                uncommitted_docs: Arc::new(Mutex::new(HashMap::new())),
             };
 
             res.spawn_maintainer_task(app_conf.auto_commit_interval, rx);
             Ok(IndexResult::new(res, tx))
-        })
+        });
+        //an index that could not be created leaves nothing behind, so creating it again can work
+        if created.is_err() && !existed {
+            if let Err(e) = fs::remove_dir_all(&path) {
+                warn!("could not remove {:?} after a failed create: {}", path, e);
+            }
+        }
+        created
     }
 
-    pub fn n_create<T: Into<TFieldType> + Debug + Send>(app_conf: &'static AppConf, fields: Vec<TantivyFiled<T>>, name: &str) -> Result<IndexResult> {
+    pub fn n_create<T: Into<TFieldType> + Debug + Send>(app_conf: &AppConf, fields: Vec<TantivyFiled<T>>, name: &str) -> Result<IndexResult> {
         info!("creating index : {}", name);
-        let path = Path::new(app_conf.index_path).join(name);
+        let path = app_conf.index_path.join(name);
         let (schema, raw_fields) = n_create_schema(fields);
-        Index::create_in_dir(path, schema.clone()).and_then(move |idx| {
-            let writer = idx.writer(app_conf.writer_buff_size)?;
+        let settings = IndexSettings::default();
+        Index::create_in_dir(&path, schema.clone()).and_then(move |idx| {
+            settings.store(&path)?;
+            let synonyms = SynonymSets::new(&settings.synonyms);
+            analysis::register(idx.tokenizers(), &settings.analyzers, &synonyms);
+            let writer = open_writer(&idx, &settings, app_conf.writer_buff_size)?;
             let reader = idx.reader()?;
             let (tx, rx) = oneshot::channel::<()>();
             let res = IndexDescriptor {
                 reader,
                 schema,
                 raw_fields,
+                settings: Arc::new(RwLock::new(settings)),
+                translog: Arc::new(Mutex::new(Translog::open(&path)?)),
+                synonyms,
+                dir: path,
                 index: idx,
-                writer: Arc::new(Mutex::new(writer)),
+                writer: Arc::new(Mutex::new(Some(writer))),
+                default_heap: app_conf.writer_buff_size,
                 uncommited_count: Arc::new(AtomicU64::new(0)),
+                uncommitted_docs: Arc::new(Mutex::new(HashMap::new())),
             };
             res.spawn_maintainer_task(app_conf.auto_commit_interval, rx);
             Ok(IndexResult::new(res, tx))
@@ -161,47 +246,412 @@ impl IndexDescriptor {
         &self.reader
     }
 
+    pub fn get_schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn get_settings(&self) -> IndexSettings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// creates or replaces a synonym set. queries see the new synonyms right away, documents that
+    /// were indexed with the old ones keep them until they are indexed again.
+    pub fn put_synonyms(&self, set: &str, rules: Vec<String>) -> Result<()> {
+        self.update_settings_with(|settings| {
+            settings.synonyms.insert(set.to_string(), rules);
+            Ok(())
+        }).map(|_| ())
+    }
+
+    /// the name of the analyzer documents go through for `field`
+    pub fn field_analyzer(&self, field: &str) -> Result<String> {
+        let f = self.schema.get_field(field)
+            .ok_or_else(|| TantivyError::InvalidArgument(format!("unknown field: {}", field)))?;
+        match self.schema.get_field_entry(f).field_type() {
+            TFieldType::Str(opts) => opts.get_indexing_options()
+                .map(|o| o.tokenizer().to_string())
+                .ok_or_else(|| TantivyError::InvalidArgument(format!("field {} is not indexed", field))),
+            _ => Err(TantivyError::InvalidArgument(format!("field {} is not a text field", field)))
+        }
+    }
+
+    /// the tokens `text` turns into with the analyzer named `analyzer`
+    pub fn analyze(&self, analyzer: &str, text: &str) -> Result<Vec<Token>> {
+        let tokenizer = self.index.tokenizers().get(analyzer)
+            .ok_or_else(|| TantivyError::InvalidArgument(format!("unknown analyzer: {}", analyzer)))?;
+        let mut tokens = Vec::new();
+        tokenizer.token_stream(text).process(&mut |t| tokens.push(t.clone()));
+        Ok(tokens)
+    }
+
+    /// what queries against the index are analyzed with, built from the current settings
+    pub fn search_analyzers(&self) -> SearchAnalyzers {
+        SearchAnalyzers::new(&self.index, &self.settings.read().unwrap().search_analyzers)
+    }
+
+    /// validates, persists and applies new settings. returns whether the index writer was replaced
+    /// to apply them, see `IndexSettings::needs_new_writer`.
+    pub fn update_settings(&self, settings: IndexSettings) -> Result<bool> {
+        self.update_settings_with(|current| {
+            *current = settings;
+            Ok(())
+        })
+    }
+
+    /// like `update_settings`, for settings derived from the current ones. the settings stay locked
+    /// from reading them to applying the result, concurrent updates can not undo each other.
+    pub fn update_settings_with<F: FnOnce(&mut IndexSettings) -> Result<()>>(&self, update: F) -> Result<bool> {
+        //writes read the settings while holding the writer lock, so it is taken first
+        let mut writer = self.writer()?;
+        let mut current = self.settings.write().unwrap();
+        let mut settings = current.clone();
+        update(&mut settings)?;
+        let mut errors = settings.validate();
+        errors.extend(settings.validate_writer(self.default_heap).err());
+        if !errors.is_empty() {
+            return Err(TantivyError::InvalidArgument(errors.join(", ")));
+        }
+        for field in settings.search_analyzers.keys() {
+            let indexed_text = self.schema.get_field(field).map(|f| match self.schema.get_field_entry(f).field_type() {
+                TFieldType::Str(opts) => opts.get_indexing_options().is_some(),
+                _ => false
+            });
+            if field == ID_FIELD || indexed_text != Some(true) {
+                return Err(TantivyError::InvalidArgument(format!("search analyzers can only be set on indexed text fields: {}", field)));
+            }
+        }
+        //documents already indexed were tokenized with the old analyzers
+        if current.analyzers != settings.analyzers {
+            return Err(TantivyError::InvalidArgument("analyzers can only be defined when the index is created".to_string()));
+        }
+        let new_writer = current.needs_new_writer(&settings);
+        if new_writer {
+            self.replace_writer(&mut writer, &settings, &current)?;
+        }
+        if let Err(e) = settings.store(&self.dir) {
+            if new_writer {
+                self.replace_writer(&mut writer, &current, &current)?;
+            }
+            return Err(e);
+        }
+        if current.synonyms != settings.synonyms {
+            self.synonyms.replace_all(&settings.synonyms);
+        }
+        *current = settings;
+        Ok(new_writer)
+    }
+
+    /// commits and swaps the writer for one built with `settings`. the old writer goes first since
+    /// it holds the lock on the index directory, when the new one can not be built the index gets a
+    /// writer built with `fallback` instead.
+    fn replace_writer(&self, writer: &mut WriterGuard, settings: &IndexSettings, fallback: &IndexSettings) -> Result<()> {
+        self.commit_writer(writer)?;
+        if let Some(old) = writer.0.take() {
+            if let Err(e) = old.wait_merging_threads() {
+                error!("merge failed while replacing the writer of {:?}: {:?}", self.dir, e);
+            }
+        }
+        match open_writer(&self.index, settings, self.default_heap) {
+            Ok(new) => {
+                *writer.0 = Some(new);
+                Ok(())
+            }
+            Err(e) => {
+                *writer.0 = Some(open_writer(&self.index, fallback, self.default_heap)?);
+                Err(e)
+            }
+        }
+    }
+
     pub fn add_document(&self, document: Doc) -> Result<u64> {
-        self.schema.parse_document(document.doc).map_err(|e| {
-            TantivyError::from(e)
-        }).and_then(|doc| {
-            let mut lock = util::acquire_mutex_lock::<IndexWriter, fn(WriterPoisonErr) -> MutexGuard<IndexWriter>>(&self.writer, None);
-            let id = lock.add_document(doc);
-            if !document.config.commit {
-                self.uncommited_count.fetch_add(1, Ordering::SeqCst);
-                Ok(id)
-            } else {
-                lock.commit().and_then(|last_id| {
-                    self.uncommited_count.store(0, Ordering::SeqCst);
-                    Ok(last_id)
-                })
+        self.parse_document(document.doc, None)
+            .map_err(TantivyError::InvalidArgument)
+            .and_then(|doc| {
+                self.add_parsed_document(doc, &document.config)
+            })
+            .map(|write| write.opstamp)
+    }
+
+    /// parses a json document and gives it an `_id`. `id` comes from the request path and wins over
+    /// a missing `_id` in the document, a different one is an error. without either a uuid is used.
+    pub fn parse_document(&self, text: &str, id: Option<&str>) -> std::result::Result<ParsedDoc, String> {
+        let mut doc = self.schema.parse_document(text).map_err(doc_parsing_err_to_string)?;
+        add_date_sort_values(&self.schema, &mut doc);
+        let id_field = match self.schema.get_field(ID_FIELD) {
+            Some(f) => f,
+            //indexes created before _id existed
+            None => return Ok(ParsedDoc { doc, id: None, replace: false, source: text.to_string() })
+        };
+        let given: Vec<String> = doc.get_all(id_field).iter()
+            .filter_map(|v| v.text())
+            .map(|v| v.to_string())
+            .collect();
+        let (id, replace) = match (given.as_slice(), id) {
+            ([], None) => {
+                let generated = Uuid::new_v4().to_simple().to_string();
+                doc.add_text(id_field, &generated);
+                (generated, false)
             }
-        })
+            ([], Some(id)) => {
+                doc.add_text(id_field, id);
+                (id.to_string(), true)
+            }
+            ([given], None) => (given.clone(), true),
+            ([given], Some(id)) if given == id => (given.clone(), true),
+            ([given], Some(id)) => return Err(format!("_id in the document ({}) does not match the one in the path ({})", given, id)),
+            _ => return Err("a document can only have one _id".to_string())
+        };
+        Ok(ParsedDoc { doc, id: Some(id), replace, source: text.to_string() })
+    }
+
+    //callers hold the writer lock, so replacing a document is atomic for other writers
+    fn write_document(&self, writer: &mut IndexWriter, doc: ParsedDoc) -> u64 {
+        if let (true, Some(ref id), Some(id_field)) = (doc.replace, &doc.id, self.schema.get_field(ID_FIELD)) {
+            writer.delete_term(Term::from_field_text(id_field, id));
+        }
+        if let Some(ref id) = doc.id {
+            let mut stored = Document::default();
+            for fv in doc.doc.field_values().iter().filter(|fv| self.schema.get_field_entry(fv.field()).is_stored()) {
+                stored.add(fv.clone());
+            }
+            self.uncommitted_docs.lock().unwrap().insert(id.clone(), Some(stored));
+        }
+        writer.add_document(doc.doc)
+    }
+
+    /// records the writes in the translog before they are handed to the writer. callers hold the
+    /// writer lock. a write that could not be logged is not applied. with `request` durability the
+    /// fsync runs on the thread serving the request, a slow disk stalls the other requests of
+    /// that thread until it returns.
+    fn log_writes(&self, entries: &[TranslogEntry]) -> Result<()> {
+        let durability = self.settings.read().unwrap().translog_durability.unwrap_or_default();
+        let mut log = self.translog.lock().unwrap();
+        Ok(log.append(entries, durability)?)
     }
 
-    pub fn search<Q: Into<SearchQuery>>(&self, q: Q) -> Result<SearchResult> {
-        q.handle(self)
+    /// an error means the document was not accepted. once it is, a failed commit is reported in
+    /// the result instead.
+    pub fn add_parsed_document(&self, doc: ParsedDoc, config: &AddDocConfig) -> Result<WriteResult> {
+        let mut lock = self.writer()?;
+        self.log_writes(&[add_entry(&doc)])?;
+        let opstamp = self.write_document(&mut lock, doc);
+        Ok(self.track_pending(&mut lock, 1, config.commit, opstamp))
+    }
+
+    /// counts `n` new pending operations, the last with `opstamp`, and commits when asked to or
+    /// when the `commit_every_docs` threshold of the index is reached
+    fn track_pending(&self, writer: &mut IndexWriter, n: u64, commit: bool, opstamp: u64) -> WriteResult {
+        let pending = self.uncommited_count.fetch_add(n, Ordering::SeqCst) + n;
+        let threshold = self.settings.read().unwrap().commit_every_docs;
+        if !commit && !threshold.map_or(false, |t| pending >= t) {
+            return WriteResult { opstamp, committed: false, commit_error: None };
+        }
+        match self.commit_writer(writer) {
+            Ok(last_id) => WriteResult { opstamp: last_id, committed: true, commit_error: None },
+            Err(e) => {
+                //the writes stay pending, the maintainer retries
+                error!("commit of {:?} failed: {:?}", self.dir, e);
+                WriteResult { opstamp, committed: false, commit_error: Some(e.to_string()) }
+            }
+        }
     }
 
-    fn spawn_maintainer_task(&self, tick_interval: Duration, exit_chan: OneShotReceiver<()>) {
+    /// commits and empties the translog. the log is only emptied once tantivy has persisted
+    /// everything in it, failing to empty it is harmless since replaying it is idempotent.
+    fn commit_writer(&self, writer: &mut IndexWriter) -> Result<u64> {
+        let last_id = match writer.commit() {
+            Ok(last_id) => last_id,
+            Err(e) => {
+                //the documents stay pending, but are not kept around for realtime gets while
+                //commits keep failing
+                self.uncommitted_docs.lock().unwrap().clear();
+                return Err(e);
+            }
+        };
+        self.uncommited_count.store(0, Ordering::SeqCst);
+        self.uncommitted_docs.lock().unwrap().clear();
+        if let Err(e) = self.translog.lock().unwrap().truncate() {
+            error!("could not truncate translog of {:?}: {:?}", self.dir, e);
+        }
+        //the reload policy only picks the commit up a little later, realtime gets need it now
+        if let Err(e) = self.reader.reload() {
+            error!("could not reload the reader of {:?}: {:?}", self.dir, e);
+        }
+        Ok(last_id)
+    }
+
+    /// adds a batch of documents holding the writer lock only once. the documents are left for the
+    /// maintainer task (or an explicit `commit`) to make them searchable.
+    pub fn add_parsed_documents(&self, docs: Vec<ParsedDoc>) -> Result<Vec<u64>> {
+        let mut lock = self.writer()?;
+        let entries: Vec<TranslogEntry> = docs.iter().map(add_entry).collect();
+        self.log_writes(&entries)?;
+        let ids: Vec<u64> = docs.into_iter().map(|doc| self.write_document(&mut lock, doc)).collect();
+        let last = ids.last().cloned().unwrap_or_else(|| lock.commit_opstamp());
+        //a failed threshold commit is logged by track_pending, the documents stay pending
+        self.track_pending(&mut lock, ids.len() as u64, false, last);
+        Ok(ids)
+    }
+
+    /// realtime lookup by `_id`. documents written (or deleted) since the last commit are served
+    /// from memory, the others from the index.
+    pub fn get_by_id(&self, id: &str) -> Result<Option<Value>> {
+        let id_field = self.id_field()?;
+        let render = |doc: &Document| serde_json::from_str(&self.schema.to_json(doc)).unwrap_or(Value::Null);
+        if let Some(pending) = self.uncommitted_docs.lock().unwrap().get(id) {
+            return Ok(pending.as_ref().map(render));
+        }
+        let searcher = self.reader.searcher();
+        let query = TermQuery::new(Term::from_field_text(id_field, id), IndexRecordOption::Basic);
+        let top = searcher.search(&query, &TopDocs::with_limit(1))?;
+        match top.first() {
+            Some((_, addr)) => Ok(Some(render(&searcher.doc(*addr)?))),
+            None => Ok(None)
+        }
+    }
+
+    /// number of operations that are not committed yet
+    pub fn pending_count(&self) -> u64 {
+        self.uncommited_count.load(Ordering::SeqCst)
+    }
+
+    /// commits what is pending and drops the writer once its merges are done, which releases the
+    /// lock on the index directory. the directory can be removed or opened again afterwards, the
+    /// descriptor and its clones can not write anymore.
+    pub fn close(&self) -> Result<()> {
+        let writer = {
+            let mut lock = self.writer()?;
+            self.commit_writer(&mut lock)?;
+            lock.0.take()
+        };
+        match writer {
+            Some(writer) => writer.wait_merging_threads(),
+            None => Ok(())
+        }
+    }
+
+    //a closed index is gone as far as the requests still holding its descriptor are concerned
+    fn writer(&self) -> Result<WriterGuard> {
+        let lock = util::acquire_mutex_lock::<Option<IndexWriter>, fn(WriterPoisonErr) -> MutexGuard<Option<IndexWriter>>>(&self.writer, None);
+        if lock.is_none() {
+            return Err(TantivyError::PathDoesNotExist(self.dir.clone()));
+        }
+        Ok(WriterGuard(lock))
+    }
+
+    pub fn commit(&self) -> Result<u64> {
+        let mut lock = self.writer()?;
+        self.commit_writer(&mut lock)
+    }
+
+    /// applies and commits whatever the translog holds, i.e. the writes that were accepted but not
+    /// committed when the index was last used. documents with an `_id` are replayed as replaces, so
+    /// entries that made it into a commit before the log was emptied are not duplicated.
+    fn replay_translog(&self) -> Result<()> {
+        let entries = self.translog.lock().unwrap().entries()?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        info!("replaying {} translog entries of {:?}", entries.len(), self.dir);
+        let mut lock = self.writer()?;
+        for entry in entries {
+            match entry {
+                TranslogEntry::Add { id, doc } => match self.parse_document(&doc, id.as_ref().map(String::as_str)) {
+                    Ok(mut parsed) => {
+                        parsed.replace = parsed.id.is_some();
+                        self.write_document(&mut lock, parsed);
+                    }
+                    Err(e) => warn!("skipping translog document {:?}: {}", id, e)
+                },
+                TranslogEntry::Delete { id } => {
+                    lock.delete_term(Term::from_field_text(self.id_field()?, &id));
+                }
+            }
+        }
+        self.commit_writer(&mut lock).map(|_| ())
+    }
+
+    fn id_field(&self) -> Result<TField> {
+        self.schema.get_field(ID_FIELD)
+            .ok_or_else(|| TantivyError::InvalidArgument("index was created without an _id field".to_string()))
+    }
+
+    /// deletes the documents with the given `_id`. like adds, deletes only become visible once
+    /// they are committed.
+    pub fn delete_by_id(&self, id: &str, commit: bool) -> Result<WriteResult> {
+        let mut lock = self.writer()?;
+        self.delete_ids(&mut lock, vec![id.to_string()], commit)
+    }
+
+    /// deletes every document matching `q`. tantivy can only delete by term, so the `_id`s of the
+    /// matches are read from their stored documents first. pending writes are committed before the
+    /// search, the uncommitted documents are matched too. returns the number of deleted documents
+    /// along with the opstamp of the last delete.
+    pub fn delete_by_query(&self, q: SearchQuery, commit: bool) -> Result<(usize, WriteResult)> {
+        let id_field = self.id_field()?;
+        let query = q.into_query(&self.search_analyzers()).map_err(|e| TantivyError::InvalidArgument(e.to_string()))?;
+        //the lock is held until the deletes are written, nothing is added between the search and them
+        let mut lock = self.writer()?;
+        if self.pending_count() > 0 {
+            self.commit_writer(&mut lock)?;
+        }
+        let searcher = self.reader.searcher();
+        let mut ids = HashSet::new();
+        for addr in searcher.search(&query, &DocAddresses)? {
+            if let Some(id) = searcher.doc(addr)?.get_first(id_field).and_then(TValue::text) {
+                ids.insert(id.to_string());
+            }
+        }
+        let deleted = ids.len();
+        self.delete_ids(&mut lock, ids.into_iter().collect(), commit).map(|write| (deleted, write))
+    }
+
+    fn delete_ids(&self, lock: &mut WriterGuard, ids: Vec<String>, commit: bool) -> Result<WriteResult> {
+        let id_field = self.id_field()?;
+        let entries: Vec<TranslogEntry> = ids.iter().map(|id| TranslogEntry::Delete { id: id.clone() }).collect();
+        self.log_writes(&entries)?;
+        let count = ids.len() as u64;
+        let mut opstamp = lock.commit_opstamp();
+        let mut uncommitted = self.uncommitted_docs.lock().unwrap();
+        for id in ids {
+            opstamp = lock.delete_term(Term::from_field_text(id_field, &id));
+            uncommitted.insert(id, None);
+        }
+        drop(uncommitted);
+        Ok(self.track_pending(lock, count, commit, opstamp))
+    }
+
+    pub fn search<Q: Into<SearchQuery>>(&self, q: Q, page: &Pagination) -> Result<SearchResult> {
+        if page.search_after.is_some() && page.from > 0 {
+            return Err(TantivyError::InvalidArgument("from can not be used together with search_after".to_string()));
+        }
+        page.window(self.settings.read().unwrap().max_result_window)?;
+        q.handle(self, page)
+    }
+
+    /// commits pending operations every `auto_commit_interval`. the interval is read again after
+    /// every cycle, so changing it in the settings takes effect without reopening the index.
+    fn spawn_maintainer_task(&self, default_interval: Duration, exit_chan: OneShotReceiver<()>) {
         info!("spawning maintainer task for index");
         let idx = self.clone();
 
-        let interval = Interval::new(Instant::now(), tick_interval).for_each(move |_| {
-            info!("starting maintainance cycle");
-            if idx.uncommited_count.load(Ordering::SeqCst) > 0 {
-                let mut writer = util::acquire_mutex_lock::<IndexWriter, fn(WriterPoisonErr) -> MutexGuard<IndexWriter>>(&idx.writer, None);
-                writer.commit().map_err(|err| {
-                    error!("error occured: {:?}", err);
-                    Error::shutdown()
-                }).and_then(|_x| {
-                    idx.uncommited_count.store(0, Ordering::SeqCst);
-                    Ok(())
-                })
-            } else {
-                info!("nothing to clean up. getting back to sleep");
-                Ok(())
-            }
+        let interval = future::loop_fn((), move |_| {
+            let idx = idx.clone();
+            let tick = idx.get_settings().auto_commit_interval
+                .map(Duration::from_secs)
+                .unwrap_or(default_interval);
+            Delay::new(Instant::now() + tick).and_then(move |_| {
+                info!("starting maintainance cycle");
+                if idx.uncommited_count.load(Ordering::SeqCst) > 0 {
+                    idx.writer().and_then(|mut writer| idx.commit_writer(&mut writer)).map_err(|err| {
+                        error!("error occured: {:?}", err);
+                        Error::shutdown()
+                    }).map(|_x| Loop::<(), ()>::Continue(()))
+                } else {
+                    info!("nothing to clean up. getting back to sleep");
+                    Ok(Loop::Continue(()))
+                }
+            })
         });
         let chan_interval = interval.select2(exit_chan.map_err(|err| {
             error!("sender channel closed before receiving : {:?}", err);
@@ -222,25 +672,65 @@ impl Deref for IndexDescriptor {
     }
 }
 
+fn add_entry(doc: &ParsedDoc) -> TranslogEntry {
+    TranslogEntry::Add { id: doc.id.clone(), doc: doc.source.clone() }
+}
+
+/// writer memory and thread count come from the index settings, `default_heap` is the one of the
+/// server config
+fn open_writer(idx: &Index, settings: &IndexSettings, default_heap: usize) -> Result<IndexWriter> {
+    settings.validate_writer(default_heap).map_err(TantivyError::InvalidArgument)?;
+    let (threads, heap) = settings.writer_threads_and_heap(default_heap);
+    idx.writer_with_num_threads(threads, heap)
+}
+
 fn create_schema(fields: Vec<Field>) -> Schema {
     let mut schema_builder = Schema::builder();
+    schema_builder.add_text_field(ID_FIELD, STRING | STORED);
 
     for f in fields {
         match f.field_type {
             FieldType::Text(c) => schema_builder.add_text_field::<TextIndexConfig>(&f.name, c.into()),
             FieldType::UInt64(c) => schema_builder.add_u64_field::<NumberIndexConfig>(&f.name, c.into()),
             FieldType::Int64(c) => schema_builder.add_i64_field::<NumberIndexConfig>(&f.name, c.into()),
-            FieldType::Date(c) => schema_builder.add_date_field::<NumberIndexConfig>(&f.name, c.into())
+            FieldType::Date(c) => {
+                if c.fast {
+                    schema_builder.add_i64_field(&date_sort_field(&f.name), FAST);
+                }
+                schema_builder.add_date_field::<NumberIndexConfig>(&f.name, NumberIndexConfig { fast: false, ..c }.into())
+            }
         };
     }
     schema_builder.build()
 }
 
+/// sets the sort field of every fast date field to the timestamp of the date. a value given for a
+/// sort field in the document itself is dropped.
+fn add_date_sort_values(schema: &Schema, doc: &mut Document) {
+    for entry in schema.fields() {
+        if let TFieldType::Date(_) = entry.field_type() {
+            if let (Some(date), Some(sort)) = (schema.get_field(entry.name()), schema.get_field(&date_sort_field(entry.name()))) {
+                //tantivy parses a date in a json document as its timestamp
+                let timestamp = doc.get_first(date).and_then(|v| match v {
+                    TValue::Date(d) => Some(d.timestamp()),
+                    TValue::I64(t) => Some(*t),
+                    _ => None
+                });
+                doc.filter_fields(|f| f != sort);
+                if let Some(timestamp) = timestamp {
+                    doc.add_i64(sort, timestamp);
+                }
+            }
+        }
+    }
+}
+
 
 fn n_create_schema<T: Into<TFieldType> + Debug + Send>(fields: Vec<TantivyFiled<T>>) -> (Schema, Vec<TField>) {
     let mut schema_builder = Schema::builder();
-    let mut raw_fields = Vec::with_capacity(fields.len());
-    for f in fields {
+    let mut raw_fields = Vec::with_capacity(fields.len() + 1);
+    raw_fields.push(schema_builder.add_text_field(ID_FIELD, STRING | STORED));
+    for f in fields.into_iter().filter(|f| f.name != ID_FIELD) {
         match f.ft.into() {
             TFieldType::Str(opt) => raw_fields.push(schema_builder.add_text_field(&f.name, opt)),
             TFieldType::Date(opt) => raw_fields.push(schema_builder.add_date_field(&f.name, opt)),
@@ -254,8 +744,162 @@ fn n_create_schema<T: Into<TFieldType> + Debug + Send>(fields: Vec<TantivyFiled<
 }
 
 
+#[cfg(test)]
 mod test {
     use std::time::{Instant, Duration};
+    use serde_json::{json, Value};
+    use crate::config::AppConf;
+    use crate::db::{QueryDsl, SearchQuery, Pagination, SortBy};
+    use crate::db::testing::{with_app_conf, index_config};
+    use super::IndexDescriptor;
+
+    /// runs `f` on a new index with the mapping `config`, inside a runtime for the maintainer task
+    fn with_index<F: FnOnce(&IndexDescriptor) + Send + 'static>(config: Value, f: F) {
+        with_app_conf(move |app_conf| {
+            let config = index_config(config);
+            let settings = config.effective_settings();
+            let idx = IndexDescriptor::create(&app_conf, config.fields, settings, "test").unwrap().descriptor;
+            f(&idx);
+            idx.close()
+        }).unwrap();
+    }
+
+    fn index_docs(idx: &IndexDescriptor, docs: &[Value]) {
+        let parsed = docs.iter().map(|d| idx.parse_document(&d.to_string(), None).unwrap()).collect();
+        idx.add_parsed_documents(parsed).unwrap();
+        //the commit reloads the reader
+        idx.commit().unwrap();
+    }
+
+    fn hits(idx: &IndexDescriptor, query: Value) -> usize {
+        let query: SearchQuery = match query {
+            Value::String(q) => SearchQuery::FreeQ(q),
+            dsl => serde_json::from_value::<QueryDsl>(dsl).unwrap().resolve(&idx.search_analyzers()).unwrap()
+        };
+        idx.search(query, &Pagination::default()).unwrap().hits
+    }
+
+    fn text_field(name: &str, analyzer: &str, record: &str) -> Value {
+        json!({ "name": name, "field_type": { "Text": { "index_options": { "analyzer": { "Analyzed": analyzer }, "record_options": record }, "store": true } } })
+    }
+
+    #[test]
+    fn test_failed_create_leaves_no_directory() {
+        with_app_conf(|app_conf| {
+            let app_conf = AppConf { writer_buff_size: 3_000_000, ..app_conf };
+            let mut config = index_config(json!({ "fields": [text_field("title", "default", "Freq")] }));
+            //8 threads do not fit into 3MB, the writer is refused after the index files were written
+            config.settings.indexing_threads = Some(8);
+            let settings = config.effective_settings();
+            assert!(IndexDescriptor::create(&app_conf, config.fields, settings, "test").is_err());
+            assert!(!app_conf.index_path.join("test").exists());
+            Ok::<_, ()>(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_sort_by_date() {
+        let config = json!({ "fields": [
+            text_field("title", "default", "Freq"),
+            { "name": "published", "field_type": { "Date": { "stored": true, "indexed": false, "fast": true } } }
+        ] });
+        with_index(config, |idx| {
+            index_docs(idx, &[
+                json!({ "title": "b", "published": 1_559_347_200 }),
+                json!({ "title": "a", "published": 978_307_201 }),
+                json!({ "title": "c", "published": 978_307_200, "_sort_published": 2_000_000_000 }),
+            ]);
+            let page = Pagination { sort: Some(SortBy::parse("published:desc").unwrap()), ..Pagination::default() };
+            let result = idx.search(SearchQuery::AllQ(tantivy::query::AllQuery), &page).unwrap();
+            let titles: Vec<&Value> = result.docs.iter().map(|h| &h.doc["title"][0]).collect();
+            assert_eq!(titles, vec!["b", "a", "c"]);
+            assert_eq!(result.docs[1].sort, Some(json!(978_307_201)));
+        });
+    }
+
+    #[test]
+    fn test_delete_by_id_and_query() {
+        let config = json!({ "fields": [text_field("title", "default", "Freq")] });
+        with_index(config, |idx| {
+            let docs: Vec<Value> = (0..5).map(|i| json!({ "_id": i.to_string(), "title": if i < 3 { "red" } else { "blue" } })).collect();
+            index_docs(idx, &docs);
+            index_docs(idx, &[json!({ "_id": "5", "title": "red" })]);
+
+            idx.delete_by_id("0", true).unwrap();
+            idx.get_reader().reload().unwrap();
+            assert_eq!(hits(idx, json!("red")), 3);
+            assert_eq!(idx.get_by_id("0").unwrap(), None);
+
+            //matches from every segment, without the document deleted before
+            let (deleted, _) = idx.delete_by_query(SearchQuery::FreeQ("red".to_string()), true).unwrap();
+            assert_eq!(deleted, 3);
+            idx.get_reader().reload().unwrap();
+            assert_eq!(hits(idx, json!("red")), 0);
+            assert_eq!(hits(idx, json!("blue")), 2);
+            assert!(idx.get_by_id("3").unwrap().is_some());
+
+            let (deleted, _) = idx.delete_by_query(SearchQuery::FreeQ("green".to_string()), true).unwrap();
+            assert_eq!(deleted, 0);
+
+            //pending documents are committed and matched too
+            let doc = idx.parse_document(r#"{"_id": "6", "title": "blue"}"#, None).unwrap();
+            idx.add_parsed_documents(vec![doc]).unwrap();
+            let (deleted, write) = idx.delete_by_query(SearchQuery::FreeQ("blue".to_string()), false).unwrap();
+            assert_eq!(deleted, 3);
+            assert!(!write.committed);
+            assert_eq!(idx.get_by_id("6").unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn test_realtime_get_does_not_commit() {
+        let mut note = text_field("note", "default", "Freq");
+        note["field_type"]["Text"]["store"] = json!(false);
+        let config = json!({ "fields": [text_field("title", "default", "Freq"), note] });
+        with_index(config, |idx| {
+            let doc = idx.parse_document(r#"{"_id": "1", "title": "first", "note": "not stored"}"#, None).unwrap();
+            idx.add_parsed_documents(vec![doc]).unwrap();
+            //only the stored fields, like for committed documents
+            assert_eq!(idx.get_by_id("1").unwrap().unwrap(), json!({ "_id": ["1"], "title": ["first"] }));
+            assert_eq!(idx.pending_count(), 1);
+            assert_eq!(hits(idx, json!("first")), 0);
+
+            idx.delete_by_id("1", false).unwrap();
+            assert_eq!(idx.get_by_id("1").unwrap(), None);
+            assert_eq!(idx.pending_count(), 2);
+
+            index_docs(idx, &[json!({ "_id": "2", "title": "second" })]);
+            assert_eq!(idx.get_by_id("2").unwrap().unwrap()["title"], json!(["second"]));
+            assert_eq!(idx.get_by_id("1").unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn test_synonyms_in_query_strings_and_match_queries() {
+        let mut body = text_field("body", "default", "FreqPos");
+        body["field_type"]["Text"]["index_options"]["search_analyzer"] = json!("products");
+        let config = json!({
+            "fields": [text_field("title", "products", "Freq"), text_field("tags", "products", "FreqPos"), body],
+            "settings": {
+                "analyzers": { "products": { "tokenizer": "Simple", "filters": ["Lowercase", { "Synonyms": "products" }] } },
+                "synonyms": { "products": ["laptop, notebook"] }
+            }
+        });
+        with_index(config, |idx| {
+            index_docs(idx, &[json!({ "title": "Notebook", "tags": "notebook bag", "body": "notebook" })]);
+            //documents indexed with the synonyms are found through either word
+            assert_eq!(hits(idx, json!("laptop")), 1);
+            assert_eq!(hits(idx, json!("title:laptop")), 1);
+            assert_eq!(hits(idx, json!("tags:\"laptop bag\"")), 1);
+            assert_eq!(hits(idx, json!({ "match": { "field": "title", "value": "laptop" } })), 1);
+            assert_eq!(hits(idx, json!({ "phrase": { "field": "tags", "value": "laptop bag" } })), 1);
+            //synonyms only in the search analyzer are expanded at search time, phrases are not
+            assert_eq!(hits(idx, json!({ "match": { "field": "body", "value": "laptop" } })), 1);
+            assert_eq!(hits(idx, json!("body:laptop")), 1);
+            assert_eq!(hits(idx, json!("body:laptop AND title:notebook")), 1);
+            assert_eq!(hits(idx, json!("body:laptop -tags:bag")), 0);
+        });
+    }
 
     #[test]
     fn test_tokio_interval() {
@@ -0,0 +1,139 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+use crate::db::config::Durability;
+
+
+pub const TRANSLOG_FILE: &str = "translog.json";
+
+/// one accepted write. documents are logged as they came in (plus the `_id` they were given)
+/// rather than as tantivy documents, so replaying them goes through the same parsing as a request.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TranslogEntry {
+    Add { id: Option<String>, doc: String },
+    Delete { id: String },
+}
+
+/// append-only log of the writes that are not committed yet, one json entry per line. it lives in
+/// the index directory and is emptied after every successful commit.
+pub struct Translog {
+    path: PathBuf,
+    file: File,
+    //a failed append could not be rolled back, the next one starts on a new line
+    torn: bool,
+}
+
+impl Translog {
+    pub fn open(index_dir: &Path) -> io::Result<Self> {
+        let path = index_dir.join(TRANSLOG_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self { path, file, torn: false })
+    }
+
+    /// the logged entries, oldest first. a process killed in the middle of a write leaves a torn
+    /// last line, lines that can not be parsed are skipped so the entries after them are kept.
+    pub fn entries(&self) -> io::Result<Vec<TranslogEntry>> {
+        let mut entries = Vec::new();
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut line = Vec::new();
+        let mut no = 0;
+        //raw bytes, a write torn inside a multi-byte character is not valid utf-8
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            no += 1;
+            match serde_json::from_slice(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => warn!("skipping line {} of translog {:?}: {}", no, self.path, e),
+            }
+            line.clear();
+        }
+        Ok(entries)
+    }
+
+    /// appends the entries with a single write, and fsyncs them when the durability asks for it.
+    /// a failed append is cut off again, so a partial write does not run into the next entry.
+    pub fn append(&mut self, entries: &[TranslogEntry], durability: Durability) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        if self.torn {
+            buf.push(b'\n');
+        }
+        for entry in entries {
+            serde_json::to_writer(&mut buf, entry)?;
+            buf.push(b'\n');
+        }
+        let len = self.file.metadata()?.len();
+        let written = self.file.write_all(&buf).and_then(|_| match durability {
+            Durability::Request => self.file.sync_data(),
+            Durability::Async => Ok(())
+        });
+        match written {
+            Ok(()) => {
+                self.torn = false;
+                Ok(())
+            }
+            Err(e) => {
+                if let Err(cut) = self.file.set_len(len) {
+                    warn!("could not cut translog {:?} back to {} bytes: {}", self.path, len, cut);
+                    self.torn = true;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.torn = false;
+        self.file.sync_data()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use super::{Translog, TranslogEntry};
+    use crate::db::config::Durability;
+
+    #[test]
+    fn test_translog_append_and_truncate() {
+        let dir = std::env::temp_dir().join(format!("nimool-translog-{}", uuid::Uuid::new_v4().to_simple()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut log = Translog::open(&dir).unwrap();
+        log.append(&[
+            TranslogEntry::Add { id: Some("1".to_string()), doc: r#"{"title": "a\nb"}"#.to_string() },
+            TranslogEntry::Delete { id: "1".to_string() },
+        ], Durability::Request).unwrap();
+        assert_eq!(log.entries().unwrap().len(), 2);
+
+        //a torn write only loses the entry it belongs to
+        log.file.write_all(b"{\"op\": \"del").unwrap();
+        assert_eq!(log.entries().unwrap()[1], TranslogEntry::Delete { id: "1".to_string() });
+
+        //an append that could not be cut back starts on a new line, the entries after it are kept
+        log.torn = true;
+        log.append(&[TranslogEntry::Delete { id: "3".to_string() }], Durability::Async).unwrap();
+        assert_eq!(log.entries().unwrap().len(), 3);
+        assert_eq!(log.entries().unwrap()[2], TranslogEntry::Delete { id: "3".to_string() });
+
+        //torn inside a multi-byte character
+        let torn = serde_json::to_vec(&TranslogEntry::Add { id: None, doc: "کتاب".to_string() }).unwrap();
+        log.file.write_all(&torn[..torn.len() - 3]).unwrap();
+        assert!(std::str::from_utf8(&torn[..torn.len() - 3]).is_err());
+        log.torn = true;
+        log.append(&[TranslogEntry::Delete { id: "4".to_string() }], Durability::Async).unwrap();
+        let entries = log.entries().unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3], TranslogEntry::Delete { id: "4".to_string() });
+
+        log.truncate().unwrap();
+        log.append(&[TranslogEntry::Delete { id: "2".to_string() }], Durability::Async).unwrap();
+        assert_eq!(log.entries().unwrap(), vec![TranslogEntry::Delete { id: "2".to_string() }]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
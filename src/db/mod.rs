@@ -8,6 +8,16 @@ mod error;
 mod search;
 mod catalog;
 mod util;
+mod analysis;
+#[cfg(test)]
+pub(crate) mod testing;
 
 pub use catalog::IndexCatalog;
 pub use idx::IndexDescriptor;
+pub use config::IndexConfig;
+pub use error::{FieldMappingError, doc_parsing_err_to_string};
+pub use document::{AddDocConfig, ParsedDoc, WriteResult};
+pub use search::{QueryDsl, SearchQuery, Pagination, SortBy};
+pub use error::{QueryDslError, NimoolError, TantivyCause};
+pub use analysis::{AnalyzerDef, TokenizerDef, FilterDef, SearchAnalyzers};
+pub(crate) use config::{MAX_INDEXING_THREADS, MIN_HEAP_PER_THREAD};
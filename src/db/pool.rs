@@ -44,7 +44,7 @@ impl<T> Clone for IndexPool<T> where T: Into<TFieldType> + Debug + Send {
 }
 
 impl<T> IndexPool<T> where T: 'static + Into<TFieldType> + Debug + Send {
-    pub fn new(config: &'static AppConf) -> Self {
+    pub fn new(config: Arc<AppConf>) -> Self {
         let (tx, rx) = unbounded_channel::<IndexCommand<T>>();
         let m = HashMap::new();
         let rw = RwLock::new(m);
@@ -127,12 +127,13 @@ impl<T> IndexPool<T> where T: 'static + Into<TFieldType> + Debug + Send {
         });
     }*/
 
-    fn spawn_using_tokio(app_conf: &'static AppConf, catalog: Arc<RwLock<HashMap<String, IndexDescriptor>>>, rx: tokio::sync::mpsc::UnboundedReceiver<IndexCommand<T>>) {
+    fn spawn_using_tokio(app_conf: Arc<AppConf>, catalog: Arc<RwLock<HashMap<String, IndexDescriptor>>>, rx: tokio::sync::mpsc::UnboundedReceiver<IndexCommand<T>>) {
         let handler = IndexCommandHandler::new(app_conf);
+        let mut shut_down_handles = HashMap::new();
         tokio::spawn(lazy(move || {
             info!("spaaaaaaaaaaaaaawned using tokio");
             rx.for_each(move |cmd| {
-                handler.handle_command(cmd, &catalog);
+                handler.handle_command(cmd, &catalog, &mut shut_down_handles);
                 Ok(())
             }).map_err(|e| { error!("error : {:?}", e) })
         }));
@@ -0,0 +1,265 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::{DocAddress, DocId, Score, SegmentLocalId, SegmentReader};
+use tantivy::{Result as TResult, TantivyError};
+
+use super::sort::{FieldSort, SegmentSort};
+
+
+/// a scored hit. hits are ranked by their sort key, then by score and, when scores are equal, by
+/// their segment id and doc id, so the ranking of a result set is the same on every request
+/// (tantivy's `TopDocs` leaves ties unordered). segment ordinals are not used, they change when a
+/// searcher is reloaded. without a sort field the key is always 0.
+#[derive(Debug, Clone, Copy)]
+pub struct RankedDoc {
+    pub key: u64,
+    pub score: Score,
+    /// the uuid of the segment, ordered like the segment ids
+    pub segment: u128,
+    pub addr: DocAddress,
+}
+
+impl RankedDoc {
+    /// `Ordering::Less` means `self` is ranked before `other`
+    fn rank_cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+            .then_with(|| other.score.partial_cmp(&self.score).unwrap_or(Ordering::Equal))
+            .then_with(|| self.segment.cmp(&other.segment))
+            .then_with(|| self.addr.1.cmp(&other.addr.1))
+    }
+}
+
+/// the uuid of a segment as a number, which orders the same way as its bytes
+pub fn segment_key(uuid: &str) -> Option<u128> {
+    u128::from_str_radix(uuid, 16).ok()
+}
+
+impl PartialEq for RankedDoc {
+    fn eq(&self, other: &Self) -> bool {
+        self.rank_cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedDoc {}
+
+impl PartialOrd for RankedDoc {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.rank_cmp(other))
+    }
+}
+
+//the binary heap keeps the worst ranked hit on top so it can be evicted
+impl Ord for RankedDoc {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank_cmp(other)
+    }
+}
+
+
+fn push_bounded(heap: &mut BinaryHeap<RankedDoc>, limit: usize, doc: RankedDoc) {
+    if heap.len() < limit {
+        heap.push(doc);
+    } else if let Some(mut worst) = heap.peek_mut() {
+        if doc < *worst {
+            *worst = doc;
+        }
+    }
+}
+
+/// collects the `limit` best ranked hits, optionally only those ranked after a given hit
+pub struct RankedTopDocs {
+    limit: usize,
+    after: Option<RankedDoc>,
+    sort: Option<FieldSort>,
+}
+
+impl RankedTopDocs {
+    pub fn with_limit(limit: usize) -> Self {
+        Self { limit, after: None, sort: None }
+    }
+
+    pub fn after(mut self, after: Option<RankedDoc>) -> Self {
+        self.after = after;
+        self
+    }
+
+    pub fn sort_by(mut self, sort: Option<FieldSort>) -> Self {
+        self.sort = sort;
+        self
+    }
+}
+
+pub struct RankedTopSegmentDocs {
+    limit: usize,
+    segment: u128,
+    segment_ord: SegmentLocalId,
+    after: Option<RankedDoc>,
+    sort: Option<SegmentSort>,
+    heap: BinaryHeap<RankedDoc>,
+}
+
+impl Collector for RankedTopDocs {
+    type Fruit = Vec<RankedDoc>;
+    type Child = RankedTopSegmentDocs;
+
+    fn for_segment(&self, segment_local_id: SegmentLocalId, segment: &SegmentReader) -> TResult<Self::Child> {
+        let sort = match self.sort {
+            Some(ref s) => Some(s.for_segment(segment)?),
+            None => None
+        };
+        let uuid = segment.segment_id().uuid_string();
+        let segment_key = segment_key(&uuid)
+            .ok_or_else(|| TantivyError::SystemError(format!("segment id is not a uuid: {}", uuid)))?;
+        Ok(RankedTopSegmentDocs {
+            limit: self.limit,
+            segment: segment_key,
+            segment_ord: segment_local_id,
+            after: self.after,
+            sort,
+            heap: BinaryHeap::with_capacity(self.limit),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Self::Fruit>) -> TResult<Self::Fruit> {
+        let mut heap = BinaryHeap::with_capacity(self.limit);
+        for doc in segment_fruits.into_iter().flatten() {
+            push_bounded(&mut heap, self.limit, doc);
+        }
+        Ok(heap.into_sorted_vec())
+    }
+}
+
+impl SegmentCollector for RankedTopSegmentDocs {
+    type Fruit = Vec<RankedDoc>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        let ranked = RankedDoc {
+            key: self.sort.as_ref().map_or(0, |s| s.key(doc)),
+            score,
+            segment: self.segment,
+            addr: DocAddress(self.segment_ord, doc),
+        };
+        if let Some(ref after) = self.after {
+            if ranked <= *after {
+                return;
+            }
+        }
+        push_bounded(&mut self.heap, self.limit, ranked);
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.heap.into_vec()
+    }
+}
+
+
+/// collects the address of every matching document, in no particular order
+pub struct DocAddresses;
+
+pub struct SegmentDocAddresses {
+    segment_ord: SegmentLocalId,
+    docs: Vec<DocAddress>,
+}
+
+impl Collector for DocAddresses {
+    type Fruit = Vec<DocAddress>;
+    type Child = SegmentDocAddresses;
+
+    fn for_segment(&self, segment_local_id: SegmentLocalId, _segment: &SegmentReader) -> TResult<Self::Child> {
+        Ok(SegmentDocAddresses {
+            segment_ord: segment_local_id,
+            docs: Vec::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Self::Fruit>) -> TResult<Self::Fruit> {
+        Ok(segment_fruits.into_iter().flatten().collect())
+    }
+}
+
+impl SegmentCollector for SegmentDocAddresses {
+    type Fruit = Vec<DocAddress>;
+
+    fn collect(&mut self, doc: DocId, _score: Score) {
+        self.docs.push(DocAddress(self.segment_ord, doc));
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.docs
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use tantivy::Index;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{Schema, INDEXED};
+    use tantivy::DocAddress;
+    use super::{RankedTopDocs, segment_key};
+
+    #[test]
+    fn test_ties_are_ranked_by_address() {
+        let mut builder = Schema::builder();
+        let n = builder.add_u64_field("n", INDEXED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        for i in 0..20u64 {
+            writer.add_document(doc!(n => i));
+        }
+        writer.commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let top = searcher.search(&AllQuery, &RankedTopDocs::with_limit(5)).unwrap();
+        let addrs: Vec<DocAddress> = top.iter().map(|d| d.addr).collect();
+        assert_eq!(addrs, (0..5).map(|d| DocAddress(0, d)).collect::<Vec<_>>());
+        assert!(searcher.search(&AllQuery, &RankedTopDocs::with_limit(0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pages_after_a_hit() {
+        let mut builder = Schema::builder();
+        let n = builder.add_u64_field("n", INDEXED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        //two segments, every hit has the same score
+        for i in 0..15u64 {
+            writer.add_document(doc!(n => i));
+            if i == 6 {
+                writer.commit().unwrap();
+            }
+        }
+        writer.commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        assert_eq!(searcher.segment_readers().len(), 2);
+
+        let all = searcher.search(&AllQuery, &RankedTopDocs::with_limit(15)).unwrap();
+        //ties are ranked by segment id, not by the ordinal the searcher gave the segment
+        let uuids: Vec<u128> = searcher.segment_readers().iter().map(|r| segment_key(&r.segment_id().uuid_string()).unwrap()).collect();
+        let ranked: Vec<(u128, u32)> = all.iter().map(|h| (uuids[h.addr.0 as usize], h.addr.1)).collect();
+        let mut expected = ranked.clone();
+        expected.sort();
+        assert_eq!(ranked, expected);
+
+        let mut paged = Vec::new();
+        let mut after = None;
+        loop {
+            let page = searcher.search(&AllQuery, &RankedTopDocs::with_limit(4).after(after)).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            after = page.last().cloned();
+            paged.extend(page.into_iter().map(|h| h.addr));
+        }
+        assert_eq!(paged, all.iter().map(|h| h.addr).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,89 @@
+use tantivy::{DocAddress, Searcher, TantivyError};
+use tantivy::Result as TResult;
+
+use super::collector::{RankedDoc, segment_key};
+
+
+/// position of a hit in a ranked result set, handed to clients as an opaque token so they can
+/// continue right after it. the segment is referenced by its id rather than its ordinal, ordinals
+/// are only meaningful within one searcher.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchCursor {
+    pub key: u64,
+    pub score: f32,
+    pub segment_id: String,
+    pub doc: u32,
+}
+
+const SEGMENT_ID_LEN: usize = 32;
+const KEY_LEN: usize = 16 + 8;
+const TOKEN_LEN: usize = KEY_LEN + SEGMENT_ID_LEN + 8;
+
+impl SearchCursor {
+    pub fn from_ranked(searcher: &Searcher, hit: &RankedDoc) -> Self {
+        Self {
+            key: hit.key,
+            score: hit.score,
+            segment_id: searcher.segment_reader(hit.addr.0).segment_id().uuid_string(),
+            doc: hit.addr.1,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{:016x}{:08x}{}{:08x}", self.key, self.score.to_bits(), self.segment_id, self.doc)
+    }
+
+    pub fn decode(token: &str) -> TResult<Self> {
+        let invalid = || TantivyError::InvalidArgument(format!("invalid search_after cursor: {}", token));
+        if token.len() != TOKEN_LEN || !token.is_ascii() {
+            return Err(invalid());
+        }
+        let key = u64::from_str_radix(&token[..16], 16).map_err(|_| invalid())?;
+        let score_bits = u32::from_str_radix(&token[16..KEY_LEN], 16).map_err(|_| invalid())?;
+        let segment_id = &token[KEY_LEN..KEY_LEN + SEGMENT_ID_LEN];
+        if !segment_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let doc = u32::from_str_radix(&token[KEY_LEN + SEGMENT_ID_LEN..], 16).map_err(|_| invalid())?;
+        Ok(Self {
+            key,
+            score: f32::from_bits(score_bits),
+            segment_id: segment_id.to_string(),
+            doc,
+        })
+    }
+
+    /// maps the cursor back onto a hit of `searcher`. fails when the segment was merged away since
+    /// the cursor was handed out, continuing from a different segment layout would skip or repeat hits.
+    pub fn resolve(&self, searcher: &Searcher) -> TResult<RankedDoc> {
+        let segment = segment_key(&self.segment_id)
+            .ok_or_else(|| TantivyError::InvalidArgument(format!("invalid search_after cursor, bad segment id: {}", self.segment_id)))?;
+        searcher.segment_readers().iter()
+            .position(|r| r.segment_id().uuid_string() == self.segment_id)
+            .map(|ord| RankedDoc {
+                key: self.key,
+                score: self.score,
+                segment,
+                addr: DocAddress(ord as u32, self.doc),
+            })
+            .ok_or_else(|| TantivyError::InvalidArgument("search_after cursor is no longer valid, the index has been merged since. restart from the first page".to_string()))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::SearchCursor;
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let c = SearchCursor {
+            key: u64::max_value() - 3,
+            score: 1.25,
+            segment_id: "ed4278da930f48779bc6e684469615e9".to_string(),
+            doc: 42,
+        };
+        assert_eq!(SearchCursor::decode(&c.encode()).unwrap(), c);
+        assert!(SearchCursor::decode("not a cursor").is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::{Bound, BTreeMap};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use tantivy::DateTime;
+use tantivy::query::{
+    Query,
+    AllQuery,
+    TermQuery,
+    FuzzyTermQuery,
+    RangeQuery,
+    RegexQuery,
+    PhraseQuery,
+    BooleanQuery,
+    Occur,
+};
+use tantivy::schema::{
+    Term,
+    Field,
+    FieldEntry,
+    FieldType as TFieldType,
+    IndexRecordOption,
+    Schema,
+};
+
+use super::{SearchQuery, default_search_fields};
+use crate::db::error::QueryDslError;
+use crate::db::analysis::SearchAnalyzers;
+
+/// json representation of a query. every variant maps onto one `SearchQuery` variant, e.g.
+/// `{"term": {"field": "title", "value": "x"}}` or `{"bool": {"must": [...], "must_not": [...]}}`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryDsl {
+    Term(FieldValueDsl),
+    Fuzzy(FuzzyDsl),
+    Range(RangeDsl),
+    Regex(RegexDsl),
+    Phrase(FieldValueDsl),
+    /// analyzed text, any of its words matches. words at the same position, like synonyms, are
+    /// alternatives to each other.
+    Match(FieldValueDsl),
+    Bool(BoolDsl),
+    MatchAll(MatchAllDsl),
+    QueryString(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldValueDsl {
+    pub field: String,
+    pub value: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FuzzyDsl {
+    pub field: String,
+    pub value: String,
+    #[serde(default = "default_fuzzy_distance")]
+    pub distance: u8,
+    #[serde(default = "default_transposition")]
+    pub transposition: bool,
+    #[serde(default)]
+    pub prefix: bool,
+}
+
+fn default_fuzzy_distance() -> u8 {
+    1
+}
+
+fn default_transposition() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RangeDsl {
+    pub field: String,
+    pub gt: Option<Value>,
+    pub gte: Option<Value>,
+    pub lt: Option<Value>,
+    pub lte: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegexDsl {
+    pub field: String,
+    pub pattern: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BoolDsl {
+    #[serde(default)]
+    pub must: Vec<QueryDsl>,
+    #[serde(default)]
+    pub should: Vec<QueryDsl>,
+    #[serde(default)]
+    pub must_not: Vec<QueryDsl>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatchAllDsl {}
+
+//tantivy builds its levenshtein automata for distances up to 2
+const MAX_FUZZY_DISTANCE: u8 = 2;
+
+impl QueryDsl {
+    /// resolves field names and values against the schema of an index. the analyzers are needed
+    /// (rather than only the schema) because phrases and query strings go through the field tokenizers.
+    pub fn resolve(self, analyzers: &SearchAnalyzers) -> Result<SearchQuery, QueryDslError> {
+        let schema = analyzers.schema();
+        match self {
+            QueryDsl::MatchAll(_) => Ok(SearchQuery::AllQ(AllQuery)),
+            QueryDsl::QueryString(exp) => Ok(SearchQuery::FreeQ(exp)),
+            QueryDsl::Term(tv) => {
+                let (field, entry) = lookup_field(schema, &tv.field)?;
+                let term = value_to_term(field, entry, &tv.value)?;
+                Ok(SearchQuery::TermQ(TermQuery::new(term, record_option(entry))))
+            }
+            QueryDsl::Fuzzy(fz) => {
+                let (field, entry) = lookup_field(schema, &fz.field)?;
+                expect_text(entry, "fuzzy")?;
+                if fz.distance > MAX_FUZZY_DISTANCE {
+                    return Err(QueryDslError::Invalid(format!("fuzzy distance must be at most {}", MAX_FUZZY_DISTANCE)));
+                }
+                let term = Term::from_field_text(field, &fz.value);
+                let q = if fz.prefix {
+                    FuzzyTermQuery::new_prefix(term, fz.distance, fz.transposition)
+                } else {
+                    FuzzyTermQuery::new(term, fz.distance, fz.transposition)
+                };
+                Ok(SearchQuery::FuzzyQ(q))
+            }
+            QueryDsl::Regex(rx) => {
+                let (field, entry) = lookup_field(schema, &rx.field)?;
+                expect_text(entry, "regex")?;
+                Ok(SearchQuery::RegexQ(RegexQuery::new(rx.pattern, field)))
+            }
+            QueryDsl::Range(r) => {
+                let (field, entry) = lookup_field(schema, &r.field)?;
+                let lower = bound(field, entry, r.gt, r.gte, "gt", "gte")?;
+                let upper = bound(field, entry, r.lt, r.lte, "lt", "lte")?;
+                let value_type = entry.field_type().value_type();
+                Ok(SearchQuery::RangeQ(RangeQuery::new_term_bounds(field, value_type, &lower, &upper)))
+            }
+            QueryDsl::Phrase(ph) => {
+                let (field, entry) = lookup_field(schema, &ph.field)?;
+                expect_text(entry, "phrase")?;
+                let has_positions = match entry.field_type() {
+                    TFieldType::Str(opts) => opts.get_indexing_options().map(|o| o.index_option().has_positions()).unwrap_or(false),
+                    _ => false,
+                };
+                if !has_positions {
+                    return Err(QueryDslError::Invalid(format!("field {} is not indexed with positions, phrase queries are not possible", ph.field)));
+                }
+                let text = ph.value.as_str().ok_or_else(|| type_mismatch(&ph.field, "string", &ph.value))?;
+                let tokenizer = analyzers.phrase_tokenizer_for_field(field)
+                    .ok_or_else(|| QueryDslError::Invalid(format!("field {} has no analyzer", ph.field)))?;
+                let mut terms = Vec::new();
+                tokenizer.token_stream(text).process(&mut |token| {
+                    terms.push((token.position, Term::from_field_text(field, &token.text)));
+                });
+                match terms.len() {
+                    0 => Err(QueryDslError::Invalid(format!("phrase for field {} does not contain any token", ph.field))),
+                    1 => Ok(SearchQuery::TermQ(TermQuery::new(terms.pop().unwrap().1, record_option(entry)))),
+                    _ => Ok(SearchQuery::PhraseQ(PhraseQuery::new_with_offset(terms))),
+                }
+            }
+            QueryDsl::Match(m) => {
+                let (field, entry) = lookup_field(schema, &m.field)?;
+                expect_text(entry, "match")?;
+                let text = m.value.as_str().ok_or_else(|| type_mismatch(&m.field, "string", &m.value))?;
+                let tokenizer = analyzers.tokenizer_for_field(field)
+                    .ok_or_else(|| QueryDslError::Invalid(format!("field {} has no analyzer", m.field)))?;
+                let mut positions: BTreeMap<usize, Vec<Term>> = BTreeMap::new();
+                tokenizer.token_stream(text).process(&mut |token| {
+                    let terms = positions.entry(token.position).or_insert_with(Vec::new);
+                    let term = Term::from_field_text(field, &token.text);
+                    if !terms.contains(&term) {
+                        terms.push(term);
+                    }
+                });
+                let record = record_option(entry);
+                let clauses: Vec<(Occur, Box<dyn Query>)> = positions.into_iter().map(|(_, terms)| {
+                    let q: Box<dyn Query> = if terms.len() == 1 {
+                        Box::new(TermQuery::new(terms[0].clone(), record))
+                    } else {
+                        let alternatives: Vec<(Occur, Box<dyn Query>)> = terms.into_iter()
+                            .map(|t| (Occur::Should, Box::new(TermQuery::new(t, record)) as Box<dyn Query>))
+                            .collect();
+                        Box::new(BooleanQuery::from(alternatives))
+                    };
+                    (Occur::Should, q)
+                }).collect();
+                Ok(SearchQuery::BooleanQ(BooleanQuery::from(clauses)))
+            }
+            QueryDsl::Bool(b) => {
+                let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+                let positive = !b.must.is_empty() || !b.should.is_empty();
+                for (occur, queries) in vec![(Occur::Must, b.must), (Occur::Should, b.should), (Occur::MustNot, b.must_not)] {
+                    for q in queries {
+                        clauses.push((occur, q.resolve(analyzers)?.into_query(analyzers)?));
+                    }
+                }
+                //a boolean query without any positive clause matches nothing in tantivy
+                if !positive {
+                    clauses.push((Occur::Must, Box::new(AllQuery)));
+                }
+                Ok(SearchQuery::BooleanQ(BooleanQuery::from(clauses)))
+            }
+        }
+    }
+}
+
+impl SearchQuery {
+    /// turns the query into a tantivy query, parsing query strings along the way. used to nest
+    /// queries inside a boolean query.
+    pub fn into_query(self, analyzers: &SearchAnalyzers) -> Result<Box<dyn Query>, QueryDslError> {
+        use SearchQuery::*;
+        Ok(match self {
+            AllQ(q) => Box::new(q),
+            TermQ(q) => Box::new(q),
+            FuzzyQ(q) => Box::new(q),
+            RangeQ(q) => Box::new(q),
+            RegexQ(q) => Box::new(q),
+            PhraseQ(q) => Box::new(q),
+            BooleanQ(q) => Box::new(q),
+            FreeQ(exp) => {
+                analyzers.parse_query(default_search_fields(analyzers.schema()), &exp)
+                    .map_err(|e| QueryDslError::QueryString(format!("{:?}", e)))?
+            }
+        })
+    }
+}
+
+fn lookup_field<'a>(schema: &'a Schema, name: &str) -> Result<(Field, &'a FieldEntry), QueryDslError> {
+    let field = schema.get_field(name).ok_or_else(|| QueryDslError::UnknownField(name.to_string()))?;
+    let entry = schema.get_field_entry(field);
+    if !entry.is_indexed() {
+        return Err(QueryDslError::NotIndexed(name.to_string()));
+    }
+    Ok((field, entry))
+}
+
+fn expect_text(entry: &FieldEntry, query: &str) -> Result<(), QueryDslError> {
+    match entry.field_type() {
+        TFieldType::Str(_) => Ok(()),
+        _ => Err(QueryDslError::Invalid(format!("{} queries are only supported on text fields, {} is not a text field", query, entry.name())))
+    }
+}
+
+fn record_option(entry: &FieldEntry) -> IndexRecordOption {
+    match entry.field_type() {
+        TFieldType::Str(opts) => opts.get_indexing_options().map(|o| o.index_option()).unwrap_or(IndexRecordOption::Basic),
+        _ => IndexRecordOption::Basic
+    }
+}
+
+fn type_mismatch(field: &str, expected: &'static str, found: &Value) -> QueryDslError {
+    QueryDslError::TypeMismatch {
+        field: field.to_string(),
+        expected,
+        found: found.to_string(),
+    }
+}
+
+fn value_to_term(field: Field, entry: &FieldEntry, value: &Value) -> Result<Term, QueryDslError> {
+    let name = entry.name();
+    match entry.field_type() {
+        TFieldType::Str(_) => value.as_str()
+            .map(|s| Term::from_field_text(field, s))
+            .ok_or_else(|| type_mismatch(name, "string", value)),
+        TFieldType::U64(_) => value.as_u64()
+            .map(|v| Term::from_field_u64(field, v))
+            .ok_or_else(|| type_mismatch(name, "unsigned integer", value)),
+        TFieldType::I64(_) => value.as_i64()
+            .map(|v| Term::from_field_i64(field, v))
+            .ok_or_else(|| type_mismatch(name, "integer", value)),
+        TFieldType::Date(_) => value.as_str()
+            .and_then(|s| s.parse::<DateTime>().ok())
+            .map(|d| Term::from_field_date(field, &d))
+            .ok_or_else(|| type_mismatch(name, "rfc3339 date", value)),
+        _ => Err(QueryDslError::Invalid(format!("querying field {} is not supported", name)))
+    }
+}
+
+fn bound(field: Field, entry: &FieldEntry, excl: Option<Value>, incl: Option<Value>, excl_name: &str, incl_name: &str) -> Result<Bound<Term>, QueryDslError> {
+    match (excl, incl) {
+        (Some(_), Some(_)) => Err(QueryDslError::Invalid(format!("{} and {} can not be used together", excl_name, incl_name))),
+        (Some(v), None) => Ok(Bound::Excluded(value_to_term(field, entry, &v)?)),
+        (None, Some(v)) => Ok(Bound::Included(value_to_term(field, entry, &v)?)),
+        (None, None) => Ok(Bound::Unbounded),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use tantivy::Index;
+    use std::collections::BTreeMap;
+    use tantivy::schema::{Schema, TextOptions, TextFieldIndexing, IndexRecordOption, TEXT, STORED, INDEXED};
+    use tantivy::collector::Count;
+    use super::QueryDsl;
+    use crate::db::error::QueryDslError;
+    use crate::db::analysis::{self, SearchAnalyzers, SynonymSets, AnalyzerDef, TokenizerDef, FilterDef};
+
+    fn test_index() -> Index {
+        let mut builder = Schema::builder();
+        let title = builder.add_text_field("title", TEXT | STORED);
+        let year = builder.add_u64_field("year", INDEXED | STORED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        writer.add_document(doc!(title => "the old man and the sea", year => 1952u64));
+        writer.add_document(doc!(title => "the sun also rises", year => 1926u64));
+        writer.commit().unwrap();
+        index
+    }
+
+    fn count(index: &Index, json: &str) -> usize {
+        let dsl: QueryDsl = serde_json::from_str(json).unwrap();
+        let analyzers = SearchAnalyzers::for_index(index);
+        let q = dsl.resolve(&analyzers).unwrap().into_query(&analyzers).unwrap();
+        index.reader().unwrap().searcher().search(&q, &Count).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_dsl() {
+        let index = test_index();
+        assert_eq!(count(&index, r#"{"term": {"field": "title", "value": "sea"}}"#), 1);
+        assert_eq!(count(&index, r#"{"match_all": {}}"#), 2);
+        assert_eq!(count(&index, r#"{"range": {"field": "year", "gte": 1900, "lt": 1950}}"#), 1);
+        assert_eq!(count(&index, r#"{"phrase": {"field": "title", "value": "The Old Man"}}"#), 1);
+        assert_eq!(count(&index, r#"{"fuzzy": {"field": "title", "value": "sin"}}"#), 1);
+        assert_eq!(count(&index, r#"{"match": {"field": "title", "value": "Sun sea"}}"#), 2);
+        assert_eq!(count(&index, r#"{"bool": {"must_not": [{"query_string": "sea"}]}}"#), 1);
+        assert_eq!(count(&index, r#"{"bool": {"should": [{"regex": {"field": "title", "pattern": "ri.*"}}, {"term": {"field": "year", "value": 1952}}]}}"#), 2);
+
+        let analyzers = SearchAnalyzers::for_index(&index);
+        let dsl: QueryDsl = serde_json::from_str(r#"{"term": {"field": "nope", "value": "x"}}"#).unwrap();
+        match dsl.resolve(&analyzers) {
+            Err(QueryDslError::UnknownField(f)) => assert_eq!(f, "nope"),
+            _ => panic!("expected unknown field error"),
+        }
+        let dsl: QueryDsl = serde_json::from_str(r#"{"term": {"field": "year", "value": "x"}}"#).unwrap();
+        match dsl.resolve(&analyzers) {
+            Err(QueryDslError::TypeMismatch { .. }) => {}
+            _ => panic!("expected type mismatch error"),
+        }
+    }
+
+    #[test]
+    fn test_search_analyzers() {
+        let mut analyzers = BTreeMap::new();
+        let filters = vec![FilterDef::Lowercase, FilterDef::EdgeNgram { min_gram: 2, max_gram: 10 }];
+        analyzers.insert("prefixes".to_string(), AnalyzerDef { tokenizer: TokenizerDef::Simple, filters });
+        let mut builder = Schema::builder();
+        let title = builder.add_text_field("title", TextOptions::default()
+            .set_indexing_options(TextFieldIndexing::default().set_tokenizer("prefixes").set_index_option(IndexRecordOption::WithFreqsAndPositions)));
+        let index = Index::create_in_ram(builder.build());
+        analysis::register(index.tokenizers(), &analyzers, &SynonymSets::default());
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        writer.add_document(doc!(title => "Notebook"));
+        writer.add_document(doc!(title => "Nothing"));
+        writer.commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let count = |analyzers: &SearchAnalyzers, json: &str| {
+            let dsl: QueryDsl = serde_json::from_str(json).unwrap();
+            let q = dsl.resolve(analyzers).unwrap().into_query(analyzers).unwrap();
+            searcher.search(&q, &Count).unwrap()
+        };
+
+        //a search analyzer takes the place of the index analyzer, a prefix is one token
+        let mut search = BTreeMap::new();
+        search.insert("title".to_string(), "default".to_string());
+        let swapped = SearchAnalyzers::new(&index, &search);
+        let mut tokens = Vec::new();
+        swapped.tokenizer_for_field(title).unwrap().token_stream("Note").process(&mut |t| tokens.push(t.text.clone()));
+        assert_eq!(tokens, vec!["note".to_string()]);
+        assert_eq!(count(&swapped, r#"{"match": {"field": "title", "value": "Note"}}"#), 1);
+        assert_eq!(count(&swapped, r#"{"match": {"field": "title", "value": "no"}}"#), 2);
+
+        //without one the query is not cut into grams either, "note" does not match "nothing" on "no"
+        let own = SearchAnalyzers::for_index(&index);
+        assert_eq!(count(&own, r#"{"match": {"field": "title", "value": "Note"}}"#), 1);
+        assert_eq!(count(&own, r#"{"query_string": "title:notebook"}"#), 1);
+    }
+}
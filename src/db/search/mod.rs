@@ -11,7 +11,6 @@ use tantivy::query::{
     RangeQuery,
     RegexQuery,
     PhraseQuery,
-    QueryParser,
     BooleanQuery};
 
 use tantivy::collector::{
@@ -29,33 +28,97 @@ use tantivy::schema::{
 };
 
 use tantivy::Result as TResult;
+use tantivy::TantivyError;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use tantivy::schema::{Schema, FieldType as TFieldType};
 use crate::db::IndexDescriptor;
 
+mod dsl;
+mod collector;
+mod cursor;
+mod sort;
+
+pub use dsl::QueryDsl;
+pub use sort::{SortBy, SortOrder};
+use collector::RankedTopDocs;
+pub use collector::DocAddresses;
+use cursor::SearchCursor;
+
+/// which slice of the ranked hits a search returns. `search_after` takes the cursor of a previous
+/// result and continues right after its last hit, instead of collecting and skipping `from` hits.
+/// cursors are only valid for the same `sort`.
+#[derive(Debug, Clone)]
+pub struct Pagination {
+    pub from: usize,
+    pub size: usize,
+    pub search_after: Option<String>,
+    pub sort: Option<SortBy>,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            from: 0,
+            size: 10,
+            search_after: None,
+            sort: None,
+        }
+    }
+}
+
+impl Pagination {
+    /// the number of hits to collect, `from + size`, refused over `max_result_window`
+    pub fn window(&self, max_result_window: usize) -> TResult<usize> {
+        let window = self.from.checked_add(self.size)
+            .ok_or_else(|| TantivyError::InvalidArgument(format!("result window is too large, from + size overflows: {} + {}", self.from, self.size)))?;
+        if window > max_result_window {
+            return Err(TantivyError::InvalidArgument(format!(
+                "result window is too large, from + size must be less than or equal to {} but was {}",
+                max_result_window, window)));
+        }
+        Ok(window)
+    }
+}
+
 pub trait QueryHandler {
-    fn handle(self, reader: &IndexDescriptor) -> TResult<SearchResult>;
+    fn handle(self, reader: &IndexDescriptor, page: &Pagination) -> TResult<SearchResult>;
 }
 
 impl<T> QueryHandler for T where T: Into<SearchQuery> {
-    fn handle(self, idx_desc: &IndexDescriptor) -> TResult<SearchResult> {
+    fn handle(self, idx_desc: &IndexDescriptor, page: &Pagination) -> TResult<SearchResult> {
         use SearchQuery::*;
         match self.into() {
-            AllQ(q) => handle_query(idx_desc.get_reader(), &q),
-            TermQ(q) => handle_query(idx_desc.get_reader(), &q),
-            FuzzyQ(q) => handle_query(idx_desc.get_reader(), &q),
-            RangeQ(q) => handle_query(idx_desc.get_reader(), &q),
-            RegexQ(q) => handle_query(idx_desc.get_reader(), &q),
-            PhraseQ(q) => handle_query(idx_desc.get_reader(), &q),
-            BooleanQ(q) => handle_query(idx_desc.get_reader(), &q),
+            AllQ(q) => handle_query(idx_desc.get_reader(), &q, page),
+            TermQ(q) => handle_query(idx_desc.get_reader(), &q, page),
+            FuzzyQ(q) => handle_query(idx_desc.get_reader(), &q, page),
+            RangeQ(q) => handle_query(idx_desc.get_reader(), &q, page),
+            RegexQ(q) => handle_query(idx_desc.get_reader(), &q, page),
+            PhraseQ(q) => handle_query(idx_desc.get_reader(), &q, page),
+            BooleanQ(q) => handle_query(idx_desc.get_reader(), &q, page),
             FreeQ(exp) => {
-                let qp = QueryParser::for_index(idx_desc, idx_desc.get_raw_fields());
-                let q = qp.parse_query(&exp)?;
-                handle_query(idx_desc.get_reader(), &q)
+                let q = idx_desc.search_analyzers().parse_query(default_search_fields(idx_desc.get_schema()), &exp)?;
+                handle_query(idx_desc.get_reader(), &q, page)
             }
         }
     }
 }
 
 
+/// fields a query string searches when it does not name a field. only indexed text fields can take
+/// free text, an int field in the list would make the parser reject every plain word.
+pub fn default_search_fields(schema: &Schema) -> Vec<Field> {
+    schema.fields().iter()
+        .filter(|e| e.is_indexed())
+        .filter(|e| match e.field_type() {
+            TFieldType::Str(_) => true,
+            _ => false
+        })
+        .filter_map(|e| schema.get_field(e.name()))
+        .collect()
+}
+
+
 #[derive(Debug, Clone)]
 pub enum SearchQuery {
     AllQ(AllQuery),
@@ -69,12 +132,31 @@ pub enum SearchQuery {
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+pub struct Hit {
+    pub score: f32,
+    /// value of the sort field, only present when the search was sorted by a field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<Value>,
+    pub doc: Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct SearchResult {
+    #[serde(serialize_with = "serialize_millis")]
     pub took: Duration,
+    #[serde(rename = "total_hits")]
     pub hits: usize,
+    #[serde(rename = "max_score")]
     pub top_doc_score: f32,
-    pub docs: Vec<(f32, Document)>,
+    #[serde(rename = "hits")]
+    pub docs: Vec<Hit>,
+    /// pass this as `search_after` to get the page following this one
+    pub cursor: Option<String>,
+}
+
+fn serialize_millis<S: Serializer>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(d.as_secs() * 1000 + u64::from(d.subsec_millis()))
 }
 
 
@@ -85,11 +167,16 @@ impl SearchResult {
             hits: 0,
             top_doc_score: 0.0,
             docs: Vec::with_capacity(cap),
+            cursor: None,
         }
     }
 
-    pub fn add_doc(&mut self, d: Document, score: f32) {
-        self.docs.push((score, d))
+    pub fn add_doc(&mut self, d: Value, score: f32) {
+        self.docs.push(Hit { score, sort: None, doc: d })
+    }
+
+    pub fn add_sorted_doc(&mut self, d: Value, score: f32, sort: Value) {
+        self.docs.push(Hit { score, sort: Some(sort), doc: d })
     }
 }
 
@@ -100,33 +187,61 @@ impl Default for SearchResult {
             hits: 0,
             top_doc_score: 0.0,
             docs: Vec::new(),
+            cursor: None,
         }
     }
 }
 
 
-fn handle_query<Q: Query>(reader: &IndexReader, q: &Q) -> TResult<SearchResult> {
+fn handle_query<Q: Query>(reader: &IndexReader, q: &Q, page: &Pagination) -> TResult<SearchResult> {
     let searcher = reader.searcher();
     let now = Instant::now();
-    let (count, score_addr) = searcher.search(q, &(Count, TopDocs::with_limit(10)))?;
-    let took = now.elapsed();
-    let mut sr = SearchResult::with_capacity(score_addr.len());
-    sr.took = took;
+    let schema = searcher.schema();
+    let sort = match page.sort {
+        Some(ref s) => Some(s.resolve(schema)?),
+        None => None
+    };
+    let (collector, skip) = match page.search_after {
+        Some(ref token) => {
+            let after = SearchCursor::decode(token)?.resolve(&searcher)?;
+            (RankedTopDocs::with_limit(page.size).after(Some(after)), 0)
+        }
+        None => (RankedTopDocs::with_limit(page.window(usize::max_value())?), page.from)
+    };
+    let (count, ranked) = searcher.search(q, &(Count, collector.sort_by(sort)))?;
+    let mut sr = SearchResult::with_capacity(page.size);
     sr.hits = count;
-    if count == 0 {
-        Ok(sr)
-    } else {
-        sr.top_doc_score = score_addr.get(0).unwrap().0;
-        for (score, id) in score_addr {
-            if let Ok(doc) = searcher.doc(id) {
-                sr.add_doc(doc, score);
-            } else {
-                warn!("document not found : {:?}", id);
-                continue;
+    //sorted by a field the first hit is not necessarily the best scored one
+    sr.top_doc_score = ranked.iter().map(|h| h.score).fold(0.0, f32::max);
+    for hit in ranked.into_iter().skip(skip) {
+        sr.cursor = Some(SearchCursor::from_ranked(&searcher, &hit).encode());
+        if let Ok(doc) = searcher.doc(hit.addr) {
+            let rendered = serde_json::from_str(&schema.to_json(&doc)).unwrap_or(Value::Null);
+            match sort {
+                Some(ref s) => sr.add_sorted_doc(rendered, hit.score, s.key_to_value(hit.key)),
+                None => sr.add_doc(rendered, hit.score)
             }
+        } else {
+            warn!("document not found : {:?}", hit.addr);
+            continue;
         }
-        Ok(sr)
     }
+    //took covers fetching the stored fields as well, that is what the caller waits for
+    sr.took = now.elapsed();
+    Ok(sr)
 }
 
-
+#[cfg(test)]
+mod test {
+    use super::Pagination;
+
+    #[test]
+    fn test_result_window() {
+        let page = |from, size| Pagination { from, size, ..Pagination::default() };
+        assert_eq!(page(0, 10).window(10).unwrap(), 10);
+        assert_eq!(page(5, 5).window(usize::max_value()).unwrap(), 10);
+        assert!(page(5, 6).window(10).is_err());
+        assert!(page(usize::max_value(), 1).window(usize::max_value()).is_err());
+        assert!(page(1, usize::max_value()).window(10).is_err());
+    }
+}
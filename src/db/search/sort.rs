@@ -0,0 +1,170 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use tantivy::schema::{Schema, Field, FieldType as TFieldType};
+use tantivy::fastfield::FastFieldReader;
+use tantivy::{DocId, SegmentReader, TantivyError, i64_to_u64, u64_to_i64};
+use tantivy::Result as TResult;
+
+use crate::db::config::date_sort_field;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder::Asc
+    }
+}
+
+/// orders hits by the value of a fast field, `{"field": "price", "order": "desc"}`. hits with the
+/// same value keep their relevance order.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SortBy {
+    pub field: String,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+impl SortBy {
+    /// parses the query string form, `price` or `price:desc`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        let field = parts.next().unwrap_or("").to_string();
+        let order = match parts.next() {
+            None | Some("asc") => SortOrder::Asc,
+            Some("desc") => SortOrder::Desc,
+            Some(o) => return Err(format!("invalid sort order: {}, expected asc or desc", o))
+        };
+        if field.is_empty() {
+            return Err("sort needs a field name".to_string());
+        }
+        Ok(Self { field, order })
+    }
+
+    pub fn resolve(&self, schema: &Schema) -> TResult<FieldSort> {
+        let invalid = |reason: &str| TantivyError::InvalidArgument(format!("can not sort on {}: {}", self.field, reason));
+        let field = schema.get_field(&self.field).ok_or_else(|| invalid("no such field in schema"))?;
+        let (field, signed) = match schema.get_field_entry(field).field_type() {
+            TFieldType::U64(opts) if opts.is_fast() => (field, false),
+            TFieldType::I64(opts) if opts.is_fast() => (field, true),
+            //dates are sorted by the timestamps in their sort field
+            TFieldType::Date(_) => match schema.get_field(&date_sort_field(&self.field)) {
+                Some(sort_field) => (sort_field, true),
+                None => return Err(invalid("field is not a fast field"))
+            },
+            TFieldType::U64(_) | TFieldType::I64(_) => return Err(invalid("field is not a fast field")),
+            _ => return Err(invalid("only Int64, UInt64 and Date fields can be sorted on"))
+        };
+        Ok(FieldSort {
+            field,
+            signed,
+            desc: self.order == SortOrder::Desc,
+        })
+    }
+}
+
+/// a `SortBy` checked against the schema. sort values are mapped onto u64 keys that order the
+/// same way the hits should, so the collector only ever compares keys.
+#[derive(Debug, Clone, Copy)]
+pub struct FieldSort {
+    field: Field,
+    signed: bool,
+    desc: bool,
+}
+
+impl FieldSort {
+    pub fn for_segment(&self, segment: &SegmentReader) -> TResult<SegmentSort> {
+        //i64 values are stored in the u64 column with the order preserving mapping
+        let column = if self.signed {
+            segment.fast_field_reader::<i64>(self.field).map(SortColumn::Signed)
+        } else {
+            segment.fast_field_reader::<u64>(self.field).map(SortColumn::Unsigned)
+        };
+        column.map(|column| SegmentSort { column, desc: self.desc })
+            .map_err(|e| TantivyError::InvalidArgument(format!("{:?}", e)))
+    }
+
+    pub fn key_to_value(&self, key: u64) -> Value {
+        let raw = if self.desc { !key } else { key };
+        if self.signed {
+            Value::from(u64_to_i64(raw))
+        } else {
+            Value::from(raw)
+        }
+    }
+}
+
+enum SortColumn {
+    Signed(FastFieldReader<i64>),
+    Unsigned(FastFieldReader<u64>),
+}
+
+pub struct SegmentSort {
+    column: SortColumn,
+    desc: bool,
+}
+
+impl SegmentSort {
+    pub fn key(&self, doc: DocId) -> u64 {
+        let raw = match self.column {
+            SortColumn::Signed(ref r) => i64_to_u64(r.get(doc)),
+            SortColumn::Unsigned(ref r) => r.get(doc),
+        };
+        if self.desc { !raw } else { raw }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+    use tantivy::Index;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{Schema, FAST, STORED};
+    use crate::db::config::date_sort_field;
+    use crate::db::search::collector::RankedTopDocs;
+    use super::{SortBy, SortOrder};
+
+    fn sorted(index: &Index, field: &str, order: SortOrder) -> Vec<Value> {
+        let searcher = index.reader().unwrap().searcher();
+        let sort = SortBy { field: field.to_string(), order }.resolve(&searcher.schema()).unwrap();
+        let ranked = searcher.search(&AllQuery, &RankedTopDocs::with_limit(10).sort_by(Some(sort))).unwrap();
+        ranked.iter().map(|h| sort.key_to_value(h.key)).collect()
+    }
+
+    #[test]
+    fn test_sort_keys() {
+        let mut builder = Schema::builder();
+        let price = builder.add_i64_field("price", FAST);
+        let year = builder.add_u64_field("year", FAST);
+        builder.add_date_field("published", STORED);
+        let published = builder.add_i64_field(&date_sort_field("published"), FAST);
+        builder.add_u64_field("slow", STORED);
+        let schema = builder.build();
+        let index = Index::create_in_ram(schema.clone());
+        let mut writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        for (p, y, t) in &[(5i64, 2001u64, 1_500_000_000i64), (-3, 1999, -86_400), (0, 2010, 0)] {
+            writer.add_document(doc!(price => *p, year => *y, published => *t));
+        }
+        writer.commit().unwrap();
+
+        //i64 values keep their order on the u64 keys, negative ones included
+        assert_eq!(sorted(&index, "price", SortOrder::Asc), vec![Value::from(-3), Value::from(0), Value::from(5)]);
+        //desc inverts the keys, the values are given back as they were
+        assert_eq!(sorted(&index, "price", SortOrder::Desc), vec![Value::from(5), Value::from(0), Value::from(-3)]);
+        assert_eq!(sorted(&index, "year", SortOrder::Desc), vec![Value::from(2010u64), Value::from(2001u64), Value::from(1999u64)]);
+        //a date is sorted by the timestamps of its sort field
+        assert_eq!(sorted(&index, "published", SortOrder::Asc), vec![Value::from(-86_400), Value::from(0), Value::from(1_500_000_000)]);
+
+        assert!(SortBy::parse("slow").unwrap().resolve(&schema).is_err());
+        assert!(SortBy::parse("missing").unwrap().resolve(&schema).is_err());
+        assert!(SortBy::parse("price:sideways").is_err());
+        assert_eq!(SortBy::parse("price:desc").unwrap().order, SortOrder::Desc);
+    }
+}
@@ -0,0 +1,27 @@
+use futures::future::{self, IntoFuture};
+use serde_json::Value;
+use tokio::runtime::Runtime;
+use crate::config::AppConf;
+use super::IndexConfig;
+
+/// runs `f` inside a runtime, which the maintainer tasks of the indexes need, with an `AppConf`
+/// whose index path is a new directory under the temp dir. the directory is removed afterwards.
+pub fn with_app_conf<F, R>(f: F) -> Result<R::Item, R::Error>
+    where F: FnOnce(AppConf) -> R + Send + 'static,
+          R: IntoFuture + 'static,
+          R::Future: Send + 'static,
+          R::Item: Send + 'static,
+          R::Error: Send + 'static {
+    let dir = std::env::temp_dir().join(format!("nimool-test-{}", uuid::Uuid::new_v4().to_simple()));
+    let app_conf = AppConf { index_path: dir.clone(), ..AppConf::default() };
+    let result = Runtime::new().unwrap().block_on(future::lazy(move || f(app_conf)));
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+/// the index config `config`, with a single indexing thread so the writer fits the default buffer
+pub fn index_config(config: Value) -> IndexConfig {
+    let mut config: IndexConfig = serde_json::from_value(config).unwrap();
+    config.settings.indexing_threads = Some(1);
+    config
+}
@@ -10,10 +10,12 @@ extern crate tantivy;
 extern crate futures;
 
 extern crate tokio;
+extern crate tokio_signal;
 
 extern crate hyper;
 extern crate regex;
 extern crate bytes;
+extern crate num_cpus;
 
 mod logo;
 mod config;
@@ -48,7 +50,6 @@ use std::sync::mpsc::RecvError;
 use self::db::IndexDescriptor;
 
 
-use std::time::Duration;
 
 use tokio::prelude::*;
 use futures::future::ok;
@@ -56,21 +57,14 @@ use crate::router::{
     Route,
     NimoolRouter,
 };
+use crate::router::middleware::{TimingMiddleware, RequestIdMiddleware};
+use crate::router::body::{BodyClass, BodyLimits};
 
 use crate::router::handler;
 
 static LOGGER: SimpleLogger = SimpleLogger;
 
 
-//read this from config file later
-static APP_CONFIGURATION: AppConf = AppConf {
-    index_path: "./indexes",
-    writer_buff_size: 50_000_000,
-    listen_address: "127.0.0.1",
-    listen_port: 1969,
-    auto_commit_interval: Duration::from_secs(5),
-};
-
 use tokio::sync::oneshot::error;
 use hyper::Version;
 use std::sync::Arc;
@@ -94,25 +88,83 @@ fn main() {
     println!("{}", logo::LOGO);
     config::init_logger(LevelFilter::Debug, &LOGGER).unwrap();
 
+    let app_conf = match AppConf::load() {
+        Ok(conf) => Arc::new(conf),
+        Err(errors) => {
+            for e in errors {
+                eprintln!("configuration error: {}", e);
+            }
+            std::process::exit(1);
+        }
+    };
+    info!("configuration loaded: {:?}", app_conf);
+    if let Err(e) = std::fs::create_dir_all(&app_conf.index_path) {
+        eprintln!("could not create index directory {}: {}", app_conf.index_path.display(), e);
+        std::process::exit(1);
+    }
+
     let mut nrouter = NimoolRouter::new();
-    let mut route = Route::new_get(r"^/nimool/index/(\w*)$", handler::open_handler);
+    nrouter.set_body_limits(BodyLimits::new(&app_conf));
+    let mut route = Route::new_get(r"/nimool/index/{index:\w+}", handler::open_handler);
+    nrouter.add_route(route);
+    route = Route::new_put(r"/nimool/index/{index:\w+}", handler::create_index_handler);
+    nrouter.add_route(route);
+    route = Route::new_get(r"/nimool/_indexes", handler::list_indexes_handler).safe();
+    nrouter.add_route(route);
+    route = Route::new_get(r"/nimool/index/{index:\w+}/_mapping", handler::mapping_handler).safe();
+    nrouter.add_route(route);
+    route = Route::new_get(r"/nimool/index/{index:\w+}/_settings", handler::get_settings_handler);
+    nrouter.add_route(route);
+    route = Route::new_put(r"/nimool/index/{index:\w+}/_settings", handler::put_settings_handler);
+    nrouter.add_route(route);
+    route = Route::new_delete(r"/nimool/index/{index:\w+}", handler::delete_index_handler);
+    nrouter.add_route(route);
+    route = Route::new_post(r"/nimool/index/{index:\w+}/_close", handler::close_index_handler);
+    nrouter.add_route(route);
+    route = Route::new_post(r"/nimool/index/{index:\w+}/_doc", handler::add_doc_handler);
     nrouter.add_route(route);
-    route = Route::new_post(r"^/nimool/test$", handler::handle_post);
+    route = Route::new_put(r"/nimool/index/{index:\w+}/_doc/{id}", handler::put_doc_handler);
     nrouter.add_route(route);
+    route = Route::new_get(r"/nimool/index/{index:\w+}/_doc/{id}", handler::get_doc_handler);
+    nrouter.add_route(route);
+    route = Route::new_delete(r"/nimool/index/{index:\w+}/_doc/{id}", handler::delete_doc_handler);
+    nrouter.add_route(route);
+    route = Route::new_post(r"/nimool/index/{index:\w+}/_bulk", handler::bulk_handler).body_class(BodyClass::Bulk);
+    nrouter.add_route(route);
+    route = Route::new_post(r"/nimool/index/{index:\w+}/_delete_by_query", handler::delete_by_query_handler).body_class(BodyClass::Search);
+    nrouter.add_route(route);
+    route = Route::new_post(r"/nimool/index/{index:\w+}/_search", handler::search_handler).body_class(BodyClass::Search);
+    nrouter.add_route(route);
+    route = Route::new_get(r"/nimool/index/{index:\w+}/_search", handler::search_get_handler);
+    nrouter.add_route(route);
+    route = Route::new_post(r"/nimool/index/{index:\w+}/_analyze", handler::analyze_handler);
+    nrouter.add_route(route);
+    route = Route::new_put(r"/nimool/index/{index:\w+}/_synonyms/{set:\w+}", handler::put_synonyms_handler);
+    nrouter.add_route(route);
+    nrouter.add_middleware(TimingMiddleware);
+    nrouter.add_middleware(RequestIdMiddleware);
+    let conflicts = nrouter.conflicts();
+    if !conflicts.is_empty() {
+        for c in conflicts {
+            eprintln!("route conflicts with an earlier one: {}", c);
+        }
+        std::process::exit(1);
+    }
 
 
-    let addr: SocketAddr = ([127, 0, 0, 1], 1969).into();
+    let addr: SocketAddr = app_conf.socket_addr();
 
 
     hyper::rt::run(future::lazy(move || {
         // Share a pool with all `Service`s
-        let catalog: IndexCatalog<DummyIntoFieldType> = IndexCatalog::new(&APP_CONFIGURATION);
+        let catalog: IndexCatalog<DummyIntoFieldType> = IndexCatalog::new(app_conf);
+        let service_catalog = catalog.clone();
         let arc_router = Arc::new(nrouter);
 
 
         let new_service = move || {
             // Move a clone of `catalog` into the `service_fn`.
-            let p = catalog.clone();
+            let p = service_catalog.clone();
             let r = arc_router.clone();
             info!("pool cloned");
             service_fn(move |req| {
@@ -120,9 +172,28 @@ fn main() {
             })
         };
 
+        //on a signal the server stops accepting connections and waits for in flight requests,
+        //only then are the indexes flushed so no acknowledged write is left behind
         let server = Server::bind(&addr)
             .serve(new_service)
-            .map_err(|e| eprintln!("server error: {}", e));
+            .with_graceful_shutdown(shutdown_signal())
+            .map_err(|e| eprintln!("server error: {}", e))
+            .and_then(move |_| {
+                info!("all connections drained. flushing indexes");
+                catalog.shutdown().then(|res| {
+                    match res {
+                        Ok(flushed) => {
+                            for (name, pending) in &flushed {
+                                info!("index {} closed, {} pending operations committed", name, pending);
+                            }
+                            let total: u64 = flushed.iter().map(|(_, pending)| pending).sum();
+                            info!("shutdown complete. {} indexes closed, {} operations committed", flushed.len(), total);
+                        }
+                        Err(e) => error!("shutdown failed: {}", e)
+                    }
+                    Ok(())
+                })
+            });
 
         println!("Listening on http://{}", addr);
 
@@ -130,6 +201,24 @@ fn main() {
     }));
 }
 
+/// resolves on the first SIGINT or SIGTERM
+fn shutdown_signal() -> impl Future<Item=(), Error=()> {
+    let ctrl_c = tokio_signal::ctrl_c().flatten_stream().map(|_| "SIGINT");
+    #[cfg(unix)]
+    let signals = {
+        use tokio_signal::unix::{Signal, SIGTERM};
+        ctrl_c.select(Signal::new(SIGTERM).flatten_stream().map(|_| "SIGTERM"))
+    };
+    #[cfg(not(unix))]
+    let signals = ctrl_c;
+    signals.into_future().map(|(signal, _)| {
+        info!("{} received. shutting down", signal.unwrap_or("signal stream closed"));
+    }).or_else(|(e, _)| {
+        //without signals the server just keeps running, like it did before graceful shutdown
+        error!("could not listen for signals: {:?}", e);
+        future::empty()
+    })
+}
+
 
 //ToDo : config does not need to be a HashMap
-//ToDo : load configuration from file
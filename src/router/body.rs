@@ -0,0 +1,132 @@
+use hyper::{Body, Chunk, Request};
+use hyper::header::CONTENT_LENGTH;
+use tokio::prelude::*;
+use std::io;
+
+use crate::config::AppConf;
+use crate::db::NimoolError;
+
+
+/// which body size limit a route is held to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyClass {
+    Document,
+    Bulk,
+    Search,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimits {
+    pub document: usize,
+    pub bulk: usize,
+    pub search: usize,
+}
+
+impl BodyLimits {
+    pub fn new(conf: &AppConf) -> Self {
+        Self {
+            document: conf.max_document_body_size,
+            bulk: conf.max_bulk_body_size,
+            search: conf.max_search_body_size,
+        }
+    }
+
+    pub fn limit(&self, class: BodyClass) -> usize {
+        match class {
+            BodyClass::Document => self.document,
+            BodyClass::Bulk => self.bulk,
+            BodyClass::Search => self.search,
+        }
+    }
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self::new(&AppConf::default())
+    }
+}
+
+/// the limit of the route a request was routed to, kept in the request extensions
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimit(pub usize);
+
+/// the declared length of a body, a missing or broken header is left for the stream to tell
+pub fn content_length(req: &Request<Body>) -> Option<usize> {
+    req.headers().get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// the body of a request as a stream of chunks. it fails with `BodyTooLarge` as soon as more than
+/// the limit of the route was received, so an oversized body is never held in memory as a whole.
+/// the stream ends after that error, the rest of the body is not read.
+pub struct BodyReader {
+    body: Body,
+    limit: usize,
+    received: usize,
+    failed: bool,
+}
+
+impl BodyReader {
+    pub fn new(req: Request<Body>) -> Self {
+        let limit = req.extensions().get::<BodyLimit>().map_or(usize::max_value(), |l| l.0);
+        Self {
+            body: req.into_body(),
+            limit,
+            received: 0,
+            failed: false,
+        }
+    }
+}
+
+impl Stream for BodyReader {
+    type Item = Chunk;
+    type Error = NimoolError;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, NimoolError> {
+        if self.failed {
+            return Ok(Async::Ready(None));
+        }
+        match self.body.poll() {
+            Ok(Async::Ready(Some(chunk))) => {
+                self.received += chunk.len();
+                if self.received > self.limit {
+                    self.failed = true;
+                    return Err(NimoolError::BodyTooLarge(self.limit));
+                }
+                Ok(Async::Ready(Some(chunk)))
+            }
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(NimoolError::Io(io::Error::new(io::ErrorKind::Other, e)))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use hyper::{Body, Request};
+    use tokio::prelude::*;
+    use crate::db::NimoolError;
+    use super::{BodyReader, BodyLimit};
+
+    #[test]
+    fn test_body_limit() {
+        let chunks: Vec<Result<&str, std::io::Error>> = vec![Ok("1234"), Ok("5678")];
+        let mut req = Request::new(Body::wrap_stream(stream::iter_result(chunks)));
+        req.extensions_mut().insert(BodyLimit(6));
+        match BodyReader::new(req).concat2().wait() {
+            Err(NimoolError::BodyTooLarge(6)) => {}
+            other => panic!("expected the body to be too large, got {:?}", other.map(|c| c.len()))
+        }
+        let chunks: Vec<Result<&str, std::io::Error>> = vec![Ok("1234"), Ok("5678"), Ok("9")];
+        let mut req = Request::new(Body::wrap_stream(stream::iter_result(chunks)));
+        req.extensions_mut().insert(BodyLimit(6));
+        let received: Vec<_> = BodyReader::new(req).then(|r| Ok::<_, ()>(r.map(|c| c.len()).map_err(|e| e.kind()))).collect().wait().unwrap();
+        assert_eq!(received, vec![Ok(4), Err("body_too_large")]);
+        let mut req = Request::new(Body::from("12345678"));
+        req.extensions_mut().insert(BodyLimit(8));
+        assert_eq!(BodyReader::new(req).concat2().wait().unwrap().len(), 8);
+    }
+}
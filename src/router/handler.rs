@@ -1,7 +1,9 @@
 use super::{
     ResponseFuture,
     GenericError,
+    Params,
 };
+use super::body::BodyReader;
 
 use tokio::prelude::*;
 use hyper::{
@@ -10,73 +12,542 @@ use hyper::{
     Response,
     StatusCode,
 };
-use crate::db::IndexCatalog;
+use crate::db::{IndexCatalog, IndexDescriptor, IndexConfig, FieldMappingError, AddDocConfig, WriteResult, QueryDsl, SearchQuery, Pagination, SortBy, NimoolError};
 use crate::DummyIntoFieldType;
+use hyper::Chunk;
+use hyper::header::{CONTENT_TYPE, HeaderValue};
+use tantivy::query::AllQuery;
 
 use serde::{
     Serialize,
     Deserialize,
 };
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct MyReqData {
-    x: usize,
-    y: f32,
-}
-
 use bytes::Buf;
+use futures::future;
+use serde_json::{json, Value};
+use std::time::Instant;
 
-pub fn open_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Option<Vec<&str>>) -> ResponseFuture {
+pub(crate) fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
     let mut resp = Response::new(Body::empty());
-    let index_name = params.unwrap()[0];
-    let x = catalog.get_index_handle(index_name)
-        .map_err(|e| {
-            error!("{:?}", e);
-            Box::new(e) as GenericError
-        }).map(move |res| {
-        if let Ok(idx) = res {
-            *resp.status_mut() = StatusCode::OK;
-            *resp.body_mut() = Body::from("index opened");
-            resp
-        } else {
+    match serde_json::to_string(body) {
+        Ok(s) => {
+            *resp.status_mut() = status;
+            *resp.body_mut() = Body::from(s);
+        }
+        Err(e) => {
+            error!("could not serialize response body: {:?}", e);
             *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            *resp.body_mut() = Body::from("internal server error");
-            resp
+            *resp.body_mut() = Body::from(r#"{"error":{"type":"internal","reason":"could not serialize response"}}"#);
         }
+    }
+    resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    resp
+}
+
+fn error_status(e: &NimoolError) -> StatusCode {
+    use NimoolError::*;
+    match e {
+        IndexNotFound(_) => StatusCode::NOT_FOUND,
+        IndexAlreadyExists(_) | WriterLocked(_) => StatusCode::CONFLICT,
+        SchemaParse(_) | DocumentParse(_) | QueryParse(_) | RequestParse(_) | InvalidArgument(_) => StatusCode::BAD_REQUEST,
+        ChannelClosed(_) => StatusCode::SERVICE_UNAVAILABLE,
+        BodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        Io(_) | Tantivy(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// `{"error": {"type", "reason", "index"}}`, the body of every error response. mapping errors
+/// additionally list the problems field by field.
+pub(crate) fn error_response(index: Option<&str>, e: &NimoolError) -> Response<Body> {
+    let status = error_status(e);
+    if status.is_server_error() {
+        error!("error on index {:?}: {:?}", index, e);
+    }
+    json_response(status, &json!({ "error": error_body(index, e) }))
+}
+
+fn error_body(index: Option<&str>, e: &NimoolError) -> Value {
+    let mut body = json!({ "type": e.kind(), "reason": e.to_string() });
+    if let Some(index) = index {
+        body["index"] = json!(index);
+    }
+    if let NimoolError::SchemaParse(ref errors) = e {
+        body["fields"] = json!(errors);
+    }
+    body
+}
+
+/// boxes the future of a handler, turning whatever error it ends with into an error response
+fn respond<F>(index: Option<String>, f: F) -> ResponseFuture
+    where F: Future<Item=Response<Body>, Error=NimoolError> + Send + 'static {
+    Box::new(f.or_else(move |e| Ok::<_, GenericError>(error_response(index.as_ref().map(String::as_str), &e))))
+}
+
+fn read_body(req: Request<Body>) -> impl Future<Item=Chunk, Error=NimoolError> {
+    BodyReader::new(req).concat2()
+}
+
+fn query_flag(params: &Params, name: &str, default: bool) -> Result<bool, NimoolError> {
+    match params.query(name) {
+        None => Ok(default),
+        Some("") | Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(v) => Err(NimoolError::InvalidArgument(format!("invalid value for {}: {}. expected true or false", name, v)))
+    }
+}
+
+fn query_usize(params: &Params, name: &str) -> Result<Option<usize>, NimoolError> {
+    match params.query(name) {
+        None => Ok(None),
+        Some(v) => v.parse::<usize>().map(Some)
+            .map_err(|_| NimoolError::InvalidArgument(format!("invalid value for {}: {}. expected a non negative integer", name, v)))
+    }
+}
+
+pub fn open_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let resp = catalog.get_index_handle(&index_name).map(move |_| {
+        json_response(StatusCode::OK, &json!({ "index": name, "opened": true }))
+    });
+    respond(Some(index_name), resp)
+}
+
+pub fn create_index_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let catalog = catalog.clone();
+    let resp = read_body(req).and_then(move |body| {
+        let mut index_config = serde_json::from_slice::<IndexConfig>(body.bytes())
+            .map_err(|e| NimoolError::SchemaParse(vec![FieldMappingError::new("", &e.to_string())]))?;
+        index_config.index_name = name.clone();
+        let errors = index_config.validate();
+        if !errors.is_empty() {
+            return Err(NimoolError::SchemaParse(errors));
+        }
+        Ok(catalog.create(index_config).map(move |idx| {
+            json_response(StatusCode::CREATED, &json!({ "index": name, "schema": idx.get_schema() }))
+        }))
+    }).flatten();
+    respond(Some(index_name), resp)
+}
+
+pub fn list_indexes_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let resp = catalog.list_indexes().map(|indexes| {
+        let indexes: Vec<Value> = indexes.into_iter()
+            .map(|(name, open)| json!({ "index": name, "status": if open { "open" } else { "closed" } }))
+            .collect();
+        json_response(StatusCode::OK, &json!({ "indexes": indexes }))
+    }).map_err(NimoolError::from);
+    respond(None, future::result(resp))
+}
+
+/// a closed index is not opened for this, its mapping is read from disk
+pub fn mapping_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let resp = catalog.mapping(&index_name).map(|mapping| json_response(StatusCode::OK, &mapping));
+    respond(Some(index_name), future::result(resp))
+}
+
+pub fn get_settings_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let resp = catalog.get_index_handle(&index_name).map(move |idx| {
+        json_response(StatusCode::OK, &json!({ "index": name, "settings": idx.get_settings() }))
+    });
+    respond(Some(index_name), resp)
+}
+
+/// merges the settings in the body into the current ones, a `null` resets a setting to the server
+/// default. when the writer settings change the writer is replaced before anything is stored.
+pub fn put_settings_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let index_handle = catalog.get_index_handle(&index_name);
+    let resp = read_body(req).join(index_handle).and_then(move |(body, idx)| {
+        let update = match serde_json::from_slice::<Value>(body.bytes())? {
+            Value::Object(update) => update,
+            _ => return Err(NimoolError::InvalidArgument("settings must be a json object".to_string()))
+        };
+        let reopened = idx.update_settings_with(|settings| {
+            *settings = settings.merged(update)?;
+            Ok(())
+        })?;
+        Ok(json_response(StatusCode::OK, &json!({ "index": name, "settings": idx.get_settings(), "reopened": reopened })))
     });
-    Box::new(x)
-}
-
-pub fn handle_post(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Option<Vec<&str>>) -> ResponseFuture {
-    let mut f = Response::new(Body::empty());
-    let resp = req.into_body()
-        .concat2().map_err(|e| {
-        error!("{:?}", e);
-        Box::new(e) as GenericError
-    })
-        .map(move |x| {
-            let bytes = x.bytes();
-            let r = serde_json::from_slice::<MyReqData>(bytes);
-
-            if let Ok(data) = r {
-                info!("POST DATA RECEIVED : {:?}", data);
-                let des = serde_json::to_string(&data).unwrap();
-                *f.status_mut() = StatusCode::OK;
-                *f.body_mut() = Body::from(des);
-            } else {
-                let e = format!("{:?}", r.err().unwrap());
-                *f.status_mut() = StatusCode::BAD_REQUEST;
-                *f.body_mut() = Body::from(e);
+    respond(Some(index_name), resp)
+}
+
+pub fn close_index_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let resp = catalog.close(&index_name).map(move |_| {
+        json_response(StatusCode::OK, &json!({ "index": name, "closed": true }))
+    });
+    respond(Some(index_name), resp)
+}
+
+pub fn delete_index_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let resp = catalog.delete(&index_name).map(move |_| {
+        json_response(StatusCode::OK, &json!({ "index": name, "deleted": true }))
+    });
+    respond(Some(index_name), resp)
+}
+
+/// the response to an accepted write, `body` with its opstamp and whether it was committed. a
+/// commit that failed is reported next to it, the write itself is not lost.
+fn write_response(status: StatusCode, mut body: Value, write: &WriteResult) -> Response<Body> {
+    body["opstamp"] = json!(write.opstamp);
+    body["committed"] = json!(write.committed);
+    if let Some(ref e) = write.commit_error {
+        body["commit_error"] = json!(e);
+    }
+    json_response(status, &body)
+}
+
+pub fn add_doc_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    index_doc(req, catalog, params)
+}
+
+/// `PUT _doc/{id}`, stores the document under `id`, replacing the one that was there
+pub fn put_doc_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    index_doc(req, catalog, params)
+}
+
+fn index_doc(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let id = params.get("id").map(str::to_string);
+    let commit = match query_flag(&params, "commit", false) {
+        Ok(c) => c,
+        Err(e) => return respond(Some(index_name), future::err(e))
+    };
+    let name = index_name.clone();
+    let index_handle = catalog.get_index_handle(&index_name);
+    let resp = read_body(req).join(index_handle).and_then(move |(body, idx)| {
+        let text = std::str::from_utf8(body.bytes()).map_err(|e| NimoolError::DocumentParse(e.to_string()))?;
+        let doc = idx.parse_document(text, id.as_ref().map(String::as_str)).map_err(NimoolError::DocumentParse)?;
+        let doc_id = doc.id.clone();
+        let write = idx.add_parsed_document(doc, &AddDocConfig { commit })?;
+        Ok(write_response(StatusCode::CREATED, json!({ "index": name, "_id": doc_id }), &write))
+    });
+    respond(Some(index_name), resp)
+}
+
+pub fn get_doc_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let id = params["id"].to_string();
+    let resp = catalog.get_index_handle(&index_name).and_then(move |idx| {
+        Ok(match idx.get_by_id(&id)? {
+            Some(doc) => json_response(StatusCode::OK, &json!({ "index": name, "_id": id, "found": true, "doc": doc })),
+            None => json_response(StatusCode::NOT_FOUND, &json!({ "index": name, "_id": id, "found": false }))
+        })
+    });
+    respond(Some(index_name), resp)
+}
+
+pub fn delete_doc_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let id = params["id"].to_string();
+    let commit = match query_flag(&params, "commit", false) {
+        Ok(c) => c,
+        Err(e) => return respond(Some(index_name), future::err(e))
+    };
+    let resp = catalog.get_index_handle(&index_name).and_then(move |idx| {
+        let write = idx.delete_by_id(&id, commit)?;
+        Ok(write_response(StatusCode::OK, json!({ "index": name, "_id": id }), &write))
+    });
+    respond(Some(index_name), resp)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SynonymsRequest {
+    synonyms: Vec<String>,
+}
+
+pub fn put_synonyms_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let set = params["set"].to_string();
+    let index_handle = catalog.get_index_handle(&index_name);
+    let resp = read_body(req).join(index_handle).and_then(move |(body, idx)| {
+        let request = serde_json::from_slice::<SynonymsRequest>(body.bytes())?;
+        let rules = request.synonyms.len();
+        idx.put_synonyms(&set, request.synonyms)?;
+        Ok(json_response(StatusCode::OK, &json!({ "index": name, "set": set, "rules": rules })))
+    });
+    respond(Some(index_name), resp)
+}
+
+/// `field` analyzes the text like the documents of that field, `analyzer` names an analyzer directly
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnalyzeRequest {
+    field: Option<String>,
+    analyzer: Option<String>,
+    text: String,
+}
+
+pub fn analyze_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let index_handle = catalog.get_index_handle(&index_name);
+    let resp = read_body(req).join(index_handle).and_then(move |(body, idx)| {
+        let request = serde_json::from_slice::<AnalyzeRequest>(body.bytes())?;
+        let analyzer = match (request.field, request.analyzer) {
+            (Some(field), None) => idx.field_analyzer(&field)?,
+            (None, Some(analyzer)) => analyzer,
+            _ => return Err(NimoolError::InvalidArgument("exactly one of field and analyzer has to be given".to_string()))
+        };
+        let tokens: Vec<Value> = idx.analyze(&analyzer, &request.text)?.into_iter()
+            .map(|t| json!({ "token": t.text, "position": t.position, "start_offset": t.offset_from, "end_offset": t.offset_to }))
+            .collect();
+        Ok(json_response(StatusCode::OK, &json!({ "index": name, "analyzer": analyzer, "tokens": tokens })))
+    });
+    respond(Some(index_name), resp)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeleteByQueryRequest {
+    query: QueryDsl,
+}
+
+pub fn delete_by_query_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let commit = match query_flag(&params, "commit", false) {
+        Ok(c) => c,
+        Err(e) => return respond(Some(index_name), future::err(e))
+    };
+    let index_handle = catalog.get_index_handle(&index_name);
+    let resp = read_body(req).join(index_handle).and_then(move |(body, idx)| {
+        //an empty body must not silently turn into a match_all
+        let request = serde_json::from_slice::<DeleteByQueryRequest>(body.bytes())?;
+        let query = request.query.resolve(&idx.search_analyzers())?;
+        let (deleted, write) = idx.delete_by_query(query, commit)?;
+        Ok(write_response(StatusCode::OK, json!({ "index": name, "deleted": deleted }), &write))
+    });
+    respond(Some(index_name), resp)
+}
+
+/// keeps track of a bulk request while its body is streamed in. lines can be split between chunks,
+/// so whatever follows the last newline of a chunk is kept in `pending` until the next one arrives.
+struct BulkState {
+    idx: IndexDescriptor,
+    pending: Vec<u8>,
+    line_no: usize,
+    items: Vec<Value>,
+    errors: bool,
+}
+
+impl BulkState {
+    fn new(idx: IndexDescriptor) -> Self {
+        Self {
+            idx,
+            pending: Vec::new(),
+            line_no: 0,
+            items: Vec::new(),
+            errors: false,
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) {
+        let mut lines = Vec::new();
+        let mut data = chunk;
+        while let Some(pos) = data.iter().position(|b| *b == b'\n') {
+            self.pending.extend_from_slice(&data[..pos]);
+            lines.push(std::mem::replace(&mut self.pending, Vec::new()));
+            data = &data[pos + 1..];
+        }
+        self.pending.extend_from_slice(data);
+        self.add_lines(lines);
+    }
+
+    fn finish(&mut self) {
+        let last = std::mem::replace(&mut self.pending, Vec::new());
+        self.add_lines(vec![last]);
+    }
+
+    fn add_lines(&mut self, lines: Vec<Vec<u8>>) {
+        //all valid documents of a chunk are added with a single writer lock
+        let mut statuses = Vec::with_capacity(lines.len());
+        let mut docs = Vec::with_capacity(lines.len());
+        for line in lines {
+            self.line_no += 1;
+            let parsed = match std::str::from_utf8(&line) {
+                Ok(text) if text.trim().is_empty() => continue,
+                Ok(text) => self.idx.parse_document(text.trim(), None),
+                Err(e) => Err(e.to_string()),
+            };
+            match parsed {
+                Ok(doc) => {
+                    statuses.push((self.line_no, Ok(doc.id.clone())));
+                    docs.push(doc);
+                }
+                Err(e) => statuses.push((self.line_no, Err(e))),
             }
-            f
-        });
-    Box::new(resp)
+        }
+        //a batch that could not be logged is not written at all. without documents the writer is
+        //left alone, so lines that are all blank or invalid do not fail on a closed index.
+        let written = if docs.is_empty() {
+            Ok(Vec::new())
+        } else {
+            self.idx.add_parsed_documents(docs).map_err(|e| e.to_string())
+        };
+        let mut opstamps = written.as_ref().map(|o| o.clone()).unwrap_or_default().into_iter();
+        for (line, status) in statuses {
+            match (status, &written) {
+                (Ok(id), Ok(_)) => self.items.push(json!({ "line": line, "status": "ok", "_id": id, "opstamp": opstamps.next() })),
+                (Ok(_), Err(e)) => self.push_error(line, e.clone()),
+                (Err(e), _) => self.push_error(line, e),
+            }
+        }
+    }
+
+    fn push_error(&mut self, line: usize, reason: String) {
+        self.errors = true;
+        self.items.push(json!({ "line": line, "status": "error", "error": reason }));
+    }
+}
+
+pub fn bulk_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let name = index_name.clone();
+    let commit = match query_flag(&params, "commit", false) {
+        Ok(c) => c,
+        Err(e) => return respond(Some(index_name), future::err(e))
+    };
+    let now = Instant::now();
+    //documents are added as their lines come in. a body over the limit stops the request, the
+    //response still lists the lines that were added before it
+    let resp = catalog.get_index_handle(&index_name).and_then(move |idx| {
+        BodyReader::new(req).then(Ok::<_, NimoolError>).fold((BulkState::new(idx), None), |(mut state, failure), chunk| {
+            match chunk {
+                Ok(chunk) => state.feed(&chunk),
+                Err(e) => return Ok::<_, NimoolError>((state, Some(e))),
+            }
+            Ok((state, failure))
+        })
+    }).and_then(move |(mut state, failure)| {
+        if let Some(e) = failure {
+            let took = now.elapsed();
+            return Ok(json_response(error_status(&e), &json!({
+                "error": error_body(Some(&name), &e),
+                "index": name,
+                "took": took.as_secs() * 1000 + u64::from(took.subsec_millis()),
+                "errors": true,
+                "committed": false,
+                "items": state.items,
+            })));
+        }
+        state.finish();
+        if commit {
+            state.idx.commit()?;
+        }
+        let took = now.elapsed();
+        Ok(json_response(StatusCode::OK, &json!({
+            "index": name,
+            "took": took.as_secs() * 1000 + u64::from(took.subsec_millis()),
+            "errors": state.errors,
+            "committed": commit,
+            "items": state.items,
+        })))
+    });
+    respond(Some(index_name), resp)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SearchRequest {
+    query: Option<QueryDsl>,
+    from: Option<usize>,
+    size: Option<usize>,
+    search_after: Option<String>,
+    sort: Option<SortBy>,
+}
+
+impl SearchRequest {
+    fn pagination(&self) -> Pagination {
+        let default = Pagination::default();
+        Pagination {
+            from: self.from.unwrap_or(default.from),
+            size: self.size.unwrap_or(default.size),
+            search_after: self.search_after.clone(),
+            sort: self.sort.clone(),
+        }
+    }
+}
+
+fn run_search(idx: &IndexDescriptor, query: SearchQuery, page: &Pagination) -> Result<Response<Body>, NimoolError> {
+    let result = idx.search(query, page)?;
+    Ok(json_response(StatusCode::OK, &result))
+}
+
+pub fn search_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let index_handle = catalog.get_index_handle(&index_name);
+    let resp = read_body(req).join(index_handle).and_then(move |(body, idx)| {
+        let search_req = if body.is_empty() {
+            SearchRequest { query: None, from: None, size: None, search_after: None, sort: None }
+        } else {
+            serde_json::from_slice::<SearchRequest>(body.bytes())?
+        };
+        let page = search_req.pagination();
+        let query = match search_req.query {
+            Some(dsl) => dsl.resolve(&idx.search_analyzers())?,
+            None => SearchQuery::AllQ(AllQuery)
+        };
+        run_search(&idx, query, &page)
+    });
+    respond(Some(index_name), resp)
+}
+
+pub fn search_get_handler(req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, params: Params) -> ResponseFuture {
+    let index_name = params["index"].to_string();
+    let q = params.query("q").map(str::to_string);
+    let sort = params.query("sort").map(|s| SortBy::parse(&s).map_err(NimoolError::InvalidArgument));
+    let search_req = match (query_usize(&params, "from"), query_usize(&params, "size"), sort.transpose()) {
+        (Ok(from), Ok(size), Ok(sort)) => SearchRequest { query: None, from, size, search_after: params.query("search_after").map(str::to_string), sort },
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return respond(Some(index_name), future::err(e))
+    };
+    let resp = catalog.get_index_handle(&index_name).and_then(move |idx| {
+        //tantivy queries are not Send, so the query is only built once we are back with the index
+        let query = match q {
+            Some(ref q) if !q.is_empty() => SearchQuery::FreeQ(q.clone()),
+            _ => SearchQuery::AllQ(AllQuery)
+        };
+        run_search(&idx, query, &search_req.pagination())
+    });
+    respond(Some(index_name), resp)
 }
 
+#[cfg(test)]
 mod test {
     use serde::{
         Serialize, Deserialize,
     };
+    use std::error::Error;
+    use std::path::PathBuf;
+    use futures::{future, Future, Stream};
+    use hyper::StatusCode;
+    use serde_json::{json, Value};
+    use tantivy::TantivyError;
+    use tantivy::directory::error::LockError;
+    use std::sync::Arc;
+    use hyper::{Body, Request};
+    use regex::Regex;
+    use crate::DummyIntoFieldType;
+    use tantivy::query::AllQuery;
+    use crate::db::{NimoolError, IndexDescriptor, IndexCatalog, SearchQuery, Pagination};
+    use crate::db::testing::{with_app_conf, index_config};
+    use crate::router::{Params, GenericError, HandlerFunc};
+    use super::{error_response, analyze_handler, search_get_handler, put_doc_handler, get_doc_handler, BulkState};
 
     #[derive(Serialize, Deserialize)]
     struct MyData {
@@ -92,6 +563,178 @@ mod test {
         };
 
         let res = serde_json::to_string(&x).unwrap();
-        println!("{}", res);
+        assert_eq!(res, r#"{"d":100,"g":2.0}"#);
+    }
+
+    #[test]
+    fn test_error_response() {
+        let body = |status: StatusCode, e: NimoolError| {
+            let resp = error_response(Some("books"), &e);
+            assert_eq!(resp.status(), status);
+            let body = resp.into_body().concat2().wait().unwrap();
+            serde_json::from_slice::<Value>(&body).unwrap()
+        };
+        let not_found = NimoolError::for_index("books", TantivyError::PathDoesNotExist(PathBuf::from("books")));
+        assert_eq!(body(StatusCode::NOT_FOUND, not_found), json!({ "error": { "type": "index_not_found", "reason": "no such index: books", "index": "books" } }));
+
+        let locked = NimoolError::from(TantivyError::LockFailure(LockError::LockBusy, None));
+        assert!(locked.source().is_some());
+        assert_eq!(body(StatusCode::CONFLICT, locked)["error"]["type"], "writer_locked");
+
+        let invalid = NimoolError::from(TantivyError::InvalidArgument("size must be positive".to_string()));
+        assert_eq!(body(StatusCode::BAD_REQUEST, invalid), json!({ "error": { "type": "invalid_argument", "reason": "size must be positive", "index": "books" } }));
+
+        assert_eq!(body(StatusCode::PAYLOAD_TOO_LARGE, NimoolError::BodyTooLarge(10))["error"]["type"], "body_too_large");
+
+        let internal = NimoolError::from(TantivyError::SystemError("no threads".to_string()));
+        assert_eq!(internal.source().unwrap().to_string(), "System error.'no threads'");
+        assert!(error_response(None, &internal).status().is_server_error());
+    }
+
+    #[test]
+    fn test_bulk_lines() {
+        let items = with_app_conf(|app_conf| {
+            let config = index_config(json!({ "fields": [
+                { "name": "title", "field_type": { "Text": { "index_options": { "analyzer": { "Analyzed": "default" }, "record_options": "Freq" }, "store": true } } }
+            ] }));
+            let settings = config.effective_settings();
+            let idx = IndexDescriptor::create(&app_conf, config.fields, settings, "books").unwrap().descriptor;
+            let mut state = BulkState::new(idx.clone());
+            //a line split between chunks, blank lines and a line that is not utf-8
+            state.feed(br#"{"_id": "1", "ti"#);
+            state.feed(b"tle\": \"a\"}\n\n   \n\xff\xfe\n");
+            //the last line has no newline, it is only added when the body ends
+            state.feed(br#"{"_id": "2", "title": "b"}"#);
+            assert_eq!(state.items.len(), 2);
+            state.finish();
+            assert!(state.errors);
+            assert!(idx.get_by_id("2").unwrap().is_some());
+            idx.close().map(|_| state.items)
+        }).unwrap();
+        let lines: Vec<(u64, &str)> = items.iter().map(|i| (i["line"].as_u64().unwrap(), i["status"].as_str().unwrap())).collect();
+        assert_eq!(lines, vec![(1, "ok"), (4, "error"), (5, "ok")]);
+        assert_eq!(items[0]["_id"], "1");
+        assert_eq!(items[2]["_id"], "2");
+    }
+
+    /// runs `f` on a catalog with the index `books`, made with the mapping `fields`, inside a runtime
+    fn with_books<T, F>(fields: Value, f: F) -> T
+        where T: Send + 'static,
+              F: FnOnce(IndexCatalog<DummyIntoFieldType>, IndexDescriptor) -> Box<dyn Future<Item=T, Error=GenericError> + Send> + Send + 'static {
+        with_app_conf(move |app_conf| {
+            let catalog: IndexCatalog<DummyIntoFieldType> = IndexCatalog::new(Arc::new(app_conf));
+            let config = index_config(json!({ "index_name": "books", "fields": fields }));
+            let handle = catalog.clone();
+            catalog.create(config).map_err(|e| panic!("{}", e)).and_then(move |idx| f(handle, idx))
+        }).unwrap()
+    }
+
+    /// calls a handler of the route `pattern` the way the router does
+    fn call(handler: HandlerFunc, catalog: &IndexCatalog<DummyIntoFieldType>, pattern: &str, req: Request<Body>) -> impl Future<Item=(StatusCode, Value), Error=GenericError> {
+        let params = Params::new(&Regex::new(pattern).unwrap(), req.uri().path(), req.uri().query()).unwrap();
+        handler(req, catalog, params).and_then(|resp| {
+            let status = resp.status();
+            resp.into_body().concat2().map_err(Into::into)
+                .map(move |body| (status, serde_json::from_slice::<Value>(&body).unwrap()))
+        })
+    }
+
+    #[test]
+    fn test_analyze() {
+        let fields = json!([
+            { "name": "title", "field_type": { "Text": { "index_options": { "analyzer": { "Analyzed": "en_stem" }, "record_options": "Freq" }, "store": true } } },
+            { "name": "year", "field_type": { "UInt64": { "stored": true, "indexed": true } } }
+        ]);
+        let responses = with_books(fields, |catalog, _| {
+            let requests = vec![
+                json!({ "field": "title", "text": "The running Foxes" }),
+                json!({ "analyzer": "raw", "text": "The running Foxes" }),
+                json!({ "analyzer": "nope", "text": "a" }),
+                json!({ "field": "nope", "text": "a" }),
+                json!({ "field": "year", "text": "1" }),
+                json!({ "field": "title", "analyzer": "raw", "text": "a" }),
+            ];
+            let calls: Vec<_> = requests.into_iter().map(|body| {
+                let req = Request::post("/nimool/index/books/_analyze").body(Body::from(body.to_string())).unwrap();
+                call(analyze_handler, &catalog, r"^/nimool/index/(?P<index>\w+)/_analyze$", req)
+            }).collect();
+            Box::new(future::join_all(calls))
+        });
+
+        //a field is analyzed with its index analyzer, tokens come with positions and offsets
+        assert_eq!(responses[0].0, StatusCode::OK);
+        assert_eq!(responses[0].1["analyzer"], "en_stem");
+        assert_eq!(responses[0].1["tokens"], json!([
+            { "token": "the", "position": 0, "start_offset": 0, "end_offset": 3 },
+            { "token": "run", "position": 1, "start_offset": 4, "end_offset": 11 },
+            { "token": "fox", "position": 2, "start_offset": 12, "end_offset": 17 },
+        ]));
+        assert_eq!(responses[1].1["tokens"], json!([{ "token": "The running Foxes", "position": 0, "start_offset": 0, "end_offset": 17 }]));
+        for (status, body) in &responses[2..] {
+            assert_eq!(*status, StatusCode::BAD_REQUEST, "{}", body);
+        }
+        assert_eq!(responses[2].1["error"]["reason"].as_str().map(|r| r.contains("unknown analyzer: nope")), Some(true));
+        assert_eq!(responses[3].1["error"]["reason"].as_str().map(|r| r.contains("unknown field: nope")), Some(true));
+    }
+
+    #[test]
+    fn test_search_get() {
+        let fields = json!([
+            { "name": "title", "field_type": { "Text": { "index_options": { "analyzer": { "Analyzed": "default" }, "record_options": "Freq" }, "store": true } } },
+            { "name": "year", "field_type": { "UInt64": { "stored": true, "indexed": true, "fast": true } } }
+        ]);
+        let responses = with_books(fields, |catalog, idx| {
+            let docs = [r#"{"title": "the red fox", "year": 1990}"#, r#"{"title": "a red hen", "year": 2010}"#, r#"{"title": "blue", "year": 2000}"#];
+            idx.add_parsed_documents(docs.iter().map(|d| idx.parse_document(d, None).unwrap()).collect()).unwrap();
+            idx.commit().unwrap();
+            idx.get_reader().reload().unwrap();
+            let queries = ["q=red", "", "q=red&sort=year:desc&size=1", "sort=year&from=1&size=1", "size=ten", "sort=year:sideways", "q=nope:red"];
+            let calls: Vec<_> = queries.iter().map(|q| {
+                let req = Request::get(format!("/nimool/index/books/_search?{}", q)).body(Body::empty()).unwrap();
+                call(search_get_handler, &catalog, r"^/nimool/index/(?P<index>\w+)/_search$", req)
+            }).collect();
+            Box::new(future::join_all(calls))
+        });
+        let titles = |body: &Value| body["hits"].as_array().unwrap().iter().map(|h| h["doc"]["title"][0].as_str().unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(responses[0].1["total_hits"], 2);
+        //without q every document matches
+        assert_eq!(responses[1].1["total_hits"], 3);
+        //size and sort apply to the matches, total_hits still counts all of them
+        assert_eq!(responses[2].1["total_hits"], 2);
+        assert_eq!(titles(&responses[2].1), vec!["a red hen"]);
+        assert_eq!(responses[2].1["hits"][0]["sort"], 2010);
+        assert_eq!(titles(&responses[3].1), vec!["blue"]);
+        for (status, body) in &responses[4..] {
+            assert_eq!(*status, StatusCode::BAD_REQUEST, "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_put_twice_and_get() {
+        let fields = json!([
+            { "name": "title", "field_type": { "Text": { "index_options": { "analyzer": { "Analyzed": "default" }, "record_options": "Freq" }, "store": true } } }
+        ]);
+        let doc_route = r"^/nimool/index/(?P<index>\w+)/_doc/(?P<id>[^/]+)$";
+        let (before_commit, after_commit, hits) = with_books(fields, move |catalog, idx| {
+            let put = |title: &str| Request::put("/nimool/index/books/_doc/1").body(Body::from(json!({ "title": title }).to_string())).unwrap();
+            let get = || Request::get("/nimool/index/books/_doc/1").body(Body::empty()).unwrap();
+            let (second, get_before, get_after) = (catalog.clone(), catalog.clone(), catalog.clone());
+            Box::new(call(put_doc_handler, &catalog, doc_route, put("first"))
+                .and_then(move |_| call(put_doc_handler, &second, doc_route, put("second")))
+                //not committed yet, the second version comes from memory
+                .and_then(move |_| call(get_doc_handler, &get_before, doc_route, get()))
+                .and_then(move |before| {
+                    idx.commit().unwrap();
+                    idx.get_reader().reload().unwrap();
+                    let hits = idx.search(SearchQuery::AllQ(AllQuery), &Pagination::default()).unwrap().hits;
+                    call(get_doc_handler, &get_after, doc_route, get()).map(move |after| (before, after, hits))
+                }))
+        });
+        for (status, body) in &[before_commit, after_commit] {
+            assert_eq!(*status, StatusCode::OK);
+            assert_eq!(body["doc"]["title"], json!(["second"]));
+        }
+        //the first version was replaced, not kept next to the second one
+        assert_eq!(hits, 1);
     }
-}
\ No newline at end of file
+}
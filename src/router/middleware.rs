@@ -0,0 +1,183 @@
+use hyper::{Body, Request, Response, Method};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::http::Extensions;
+use std::time::Instant;
+use uuid::Uuid;
+
+
+/// what the middlewares of one request share. `before` hooks leave things in `extensions` for the
+/// `after` hooks, the request itself is gone by then.
+pub struct Context {
+    pub method: Method,
+    pub path: String,
+    pub extensions: Extensions,
+}
+
+impl Context {
+    pub fn new(req: &Request<Body>) -> Self {
+        Self {
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            extensions: Extensions::new(),
+        }
+    }
+}
+
+/// runs around every handler. middlewares are called in the order they were added on the way in
+/// and in reverse order on the way out. a `before` that returns a response answers the request
+/// itself, the handler and the remaining middlewares are skipped but the `after` hooks of the
+/// middlewares that already ran still see the response.
+pub trait Middleware: Send + Sync {
+    fn before(&self, _req: &mut Request<Body>, _ctx: &mut Context) -> Option<Response<Body>> {
+        None
+    }
+
+    fn after(&self, _ctx: &Context, _resp: &mut Response<Body>) {}
+}
+
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const RESPONSE_TIME_HEADER: &str = "x-response-time";
+
+/// the id of a request, taken from its `x-request-id` header or generated
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// gives every request an id and echoes it in the response. an id sent by the client is kept, so
+/// a request can be followed across services.
+pub struct RequestIdMiddleware;
+
+impl Middleware for RequestIdMiddleware {
+    fn before(&self, req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        let given = req.headers().get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty())
+            .map(str::to_string);
+        let id = given.unwrap_or_else(|| {
+            let id = Uuid::new_v4().to_simple().to_string();
+            if let Ok(v) = HeaderValue::from_str(&id) {
+                req.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), v);
+            }
+            id
+        });
+        ctx.extensions.insert(RequestId(id));
+        None
+    }
+
+    fn after(&self, ctx: &Context, resp: &mut Response<Body>) {
+        if let Some(RequestId(id)) = ctx.extensions.get::<RequestId>() {
+            if let Ok(v) = HeaderValue::from_str(id) {
+                resp.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), v);
+            }
+        }
+    }
+}
+
+struct Started(Instant);
+
+/// adds the time spent on a request to its response, in milliseconds. it only sees the time until
+/// the response head is ready, streaming the body is not included.
+pub struct TimingMiddleware;
+
+impl Middleware for TimingMiddleware {
+    fn before(&self, _req: &mut Request<Body>, ctx: &mut Context) -> Option<Response<Body>> {
+        ctx.extensions.insert(Started(Instant::now()));
+        None
+    }
+
+    fn after(&self, ctx: &Context, resp: &mut Response<Body>) {
+        if let Some(Started(start)) = ctx.extensions.get::<Started>() {
+            let took = start.elapsed();
+            let millis = took.as_secs() as f64 * 1000.0 + f64::from(took.subsec_micros()) / 1000.0;
+            if let Ok(v) = HeaderValue::from_str(&format!("{:.3}ms", millis)) {
+                resp.headers_mut().insert(HeaderName::from_static(RESPONSE_TIME_HEADER), v);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use hyper::{Body, Request, Response};
+    use futures::future::{self, Future};
+    use crate::db::IndexCatalog;
+    use crate::db::testing::with_app_conf;
+    use crate::DummyIntoFieldType;
+    use crate::router::{NimoolRouter, Route, Params, ResponseFuture};
+    use super::{Context, Middleware, RequestIdMiddleware, REQUEST_ID_HEADER};
+
+    #[test]
+    fn test_request_id() {
+        let mut req = Request::builder().uri("/nimool/_indexes").header(REQUEST_ID_HEADER, "abc").body(Body::empty()).unwrap();
+        let mut ctx = Context::new(&req);
+        assert!(RequestIdMiddleware.before(&mut req, &mut ctx).is_none());
+        let mut resp = Response::new(Body::empty());
+        RequestIdMiddleware.after(&ctx, &mut resp);
+        assert_eq!(resp.headers()[REQUEST_ID_HEADER], "abc");
+
+        let mut req = Request::new(Body::empty());
+        let mut ctx = Context::new(&req);
+        RequestIdMiddleware.before(&mut req, &mut ctx);
+        let mut resp = Response::new(Body::empty());
+        RequestIdMiddleware.after(&ctx, &mut resp);
+        assert_eq!(resp.headers()[REQUEST_ID_HEADER], req.headers()[REQUEST_ID_HEADER]);
+        assert_eq!(resp.headers()[REQUEST_ID_HEADER].len(), 32);
+    }
+
+    /// writes its hooks into `log`, answers requests to `stop` itself
+    struct Recorder {
+        name: &'static str,
+        stop: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Recorder {
+        fn before(&self, req: &mut Request<Body>, _ctx: &mut Context) -> Option<Response<Body>> {
+            self.log.lock().unwrap().push(format!("before {}", self.name));
+            if req.uri().path() == self.stop {
+                return Some(Response::new(Body::empty()));
+            }
+            None
+        }
+
+        fn after(&self, _ctx: &Context, _resp: &mut Response<Body>) {
+            self.log.lock().unwrap().push(format!("after {}", self.name));
+        }
+    }
+
+    fn ok(_: Request<Body>, _: &IndexCatalog<DummyIntoFieldType>, _: Params) -> ResponseFuture {
+        Box::new(future::ok(Response::new(Body::empty())))
+    }
+
+    fn fail(_: Request<Body>, _: &IndexCatalog<DummyIntoFieldType>, _: Params) -> ResponseFuture {
+        Box::new(future::err("handler failed".into()))
+    }
+
+    #[test]
+    fn test_chain() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut router = NimoolRouter::new();
+        router.add_route(Route::new_get("/ok", ok));
+        router.add_route(Route::new_get("/fail", fail));
+        router.add_middleware(Recorder { name: "a", stop: "/stop", log: log.clone() });
+        router.add_middleware(Recorder { name: "b", stop: "", log: log.clone() });
+        let logs = with_app_conf(move |app_conf| {
+            let catalog: IndexCatalog<DummyIntoFieldType> = IndexCatalog::new(Arc::new(app_conf));
+            let mut logs = Vec::new();
+            for path in &["/ok", "/stop", "/fail"] {
+                let req = Request::get(*path).body(Body::empty()).unwrap();
+                let failed = router.handle_request(req, &catalog).wait().is_err();
+                logs.push((failed, log.lock().unwrap().drain(..).collect::<Vec<String>>()));
+            }
+            Ok::<_, ()>(logs)
+        }).unwrap();
+        //in the order they were added, out in reverse
+        assert_eq!(logs[0], (false, vec!["before a".to_string(), "before b".to_string(), "after b".to_string(), "after a".to_string()]));
+        //an answer from a skips b and the handler, a still sees it
+        assert_eq!(logs[1], (false, vec!["before a".to_string(), "after a".to_string()]));
+        //a failing handler skips every after hook
+        assert_eq!(logs[2], (true, vec!["before a".to_string(), "before b".to_string()]));
+    }
+}
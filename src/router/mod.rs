@@ -1,29 +1,121 @@
 use tokio::prelude::*;
-use hyper::{Body, Request, Response, Method, Error};
+use hyper::{Body, Request, Response, Method};
 use hyper::http::StatusCode;
+use hyper::header::{ALLOW, CONTENT_LENGTH, HeaderValue};
 use crate::db::IndexCatalog;
 use crate::DummyIntoFieldType;
 use futures::future::ok;
 use regex::Regex;
-use regex::Captures;
+use serde_json::json;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
+use std::ops::Index;
+use std::sync::Arc;
 
 pub type GenericError = Box<dyn std::error::Error + Send + Sync>;
 pub type ResponseFuture = Box<dyn Future<Item=Response<Body>, Error=GenericError> + Send>;
-pub type HandlerFunc = fn(Request<Body>, &IndexCatalog<DummyIntoFieldType>, Option<Vec<&str>>) -> ResponseFuture;
+pub type HandlerFunc = fn(Request<Body>, &IndexCatalog<DummyIntoFieldType>, Params) -> ResponseFuture;
 
 pub mod handler;
+pub mod middleware;
+pub mod body;
 
+use self::middleware::{Middleware, Context};
+use self::body::{BodyClass, BodyLimits, BodyLimit, content_length};
+use crate::db::NimoolError;
 
+
+/// what a route took from the request: the named segments of the path and the query string, both
+/// percent decoded
+#[derive(Debug, Default, Clone)]
+pub struct Params {
+    path: HashMap<String, String>,
+    query: HashMap<String, String>,
+}
+
+impl Params {
+    fn new(pattern: &Regex, path: &str, query: Option<&str>) -> Option<Self> {
+        let caps = pattern.captures(path)?;
+        let path = pattern.capture_names()
+            .filter_map(|name| name)
+            .filter_map(|name| caps.name(name).map(|v| (name.to_string(), percent_decode(v.as_str()))))
+            .collect();
+        Some(Self { path, query: parse_query(query.unwrap_or("")) })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.path.get(name).map(String::as_str)
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(String::as_str)
+    }
+}
+
+/// `params["index"]`, for the names a route is known to have
+impl Index<&str> for Params {
+    type Output = str;
+
+    fn index(&self, name: &str) -> &str {
+        self.get(name).unwrap_or_else(|| panic!("route has no parameter {}", name))
+    }
+}
+
+/// a repeated key keeps its first value
+fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let mut kv = pair.splitn(2, '=');
+        let k = percent_decode(kv.next().unwrap_or(""));
+        let v = percent_decode(kv.next().unwrap_or(""));
+        map.entry(k).or_insert(v);
+    }
+    map
+}
+
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+
+/// a method and a path template. `{name}` in the template matches one path segment, which the
+/// handler gets as `params["name"]`. `{name:regex}` restricts what the segment may look like.
 pub struct Route {
     method: Method,
+    template: String,
+    //the template without parameter names, two routes with the same shape match the same paths
+    shape: String,
     pattern: Regex,
     handler: HandlerFunc,
+    body_class: BodyClass,
+    safe: bool,
 }
 
 impl PartialEq for Route {
     fn eq(&self, other: &Self) -> bool {
-        self.method == other.method && self.pattern.as_str() == other.pattern.as_str()
+        self.method == other.method && self.shape == other.shape
     }
 }
 
@@ -32,20 +124,37 @@ impl Eq for Route {}
 impl Hash for Route {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.method.hash(state);
-        self.pattern.as_str().hash(state);
+        self.shape.hash(state);
     }
 }
 
 impl Route where {
-    pub fn new(meth: Method, pat: &str, handler: HandlerFunc) -> Self {
+    pub fn new(meth: Method, template: &str, handler: HandlerFunc) -> Self {
+        let (pattern, shape) = compile_template(template);
         Self {
             method: meth,
-            pattern: Regex::new(pat).unwrap(),
+            template: template.to_string(),
+            shape,
+            pattern: Regex::new(&pattern).unwrap_or_else(|e| panic!("invalid route {}: {}", template, e)),
             handler,
-
+            body_class: BodyClass::Document,
+            safe: false,
         }
     }
 
+    /// the body size limit of the route, `BodyClass::Document` unless set
+    pub fn body_class(mut self, class: BodyClass) -> Self {
+        self.body_class = class;
+        self
+    }
+
+    /// marks a GET route as free of side effects, HEAD requests to its paths are answered by
+    /// running it and dropping the body. routes that e.g. open an index are not.
+    pub fn safe(mut self) -> Self {
+        self.safe = true;
+        self
+    }
+
     pub fn new_get(pat: &str, h: HandlerFunc) -> Self {
         Self::new(Method::GET, pat, h)
     }
@@ -62,51 +171,221 @@ impl Route where {
         Self::new(Method::DELETE, pat, h)
     }
 
+    /// whether every path `other` matches is matched by this route too. a segment subsumes an
+    /// identical one, and an unconstrained parameter subsumes any single segment.
+    fn subsumes(&self, other: &Route) -> bool {
+        if self == other {
+            return true;
+        }
+        let any = format!("{{:{}}}", ANY_SEGMENT);
+        let (mine, theirs) = (segments(&self.shape), segments(&other.shape));
+        self.method == other.method && mine.len() == theirs.len() && mine.iter().zip(&theirs).all(|(m, t)| {
+            m == t || (*m == any && !t.contains('/'))
+        })
+    }
+
     pub fn is_match(&self, path: &str, meth: &Method) -> bool {
         self.pattern.is_match(path) && self.method == *meth
     }
 
-    fn handle(&self, req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>) -> ResponseFuture {
-        let path = req.uri().path().to_string();
-        let caps: Option<Vec<&str>> = self.pattern.captures(&path)
-            .map(|c| c.iter().skip(1)
-                .filter(|x| x.is_some())
-                .map(|x| x.unwrap().as_str()).collect());
-        (self.handler)(req, catalog, caps)
+    /// a body that declares to be over the limit is refused before the handler runs, the handler
+    /// finds the limit in the request for bodies without a length
+    fn handle(&self, mut req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>, limits: &BodyLimits) -> ResponseFuture {
+        let limit = limits.limit(self.body_class);
+        if content_length(&req).map_or(false, |len| len > limit) {
+            return Box::new(ok(handler::error_response(None, &NimoolError::BodyTooLarge(limit))));
+        }
+        req.extensions_mut().insert(BodyLimit(limit));
+        let params = Params::new(&self.pattern, req.uri().path(), req.uri().query()).unwrap_or_default();
+        (self.handler)(req, catalog, params)
     }
 }
 
+/// the constraint of a parameter without one
+const ANY_SEGMENT: &str = "[^/]+";
+
+/// the path segments of a shape, a slash inside a parameter constraint does not split
+fn segments(shape: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in shape.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '/' if depth == 0 => {
+                segments.push(&shape[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    segments.push(&shape[start..]);
+    segments
+}
+
+/// turns a route template into an anchored regex with a named group per parameter, and into its
+/// shape, the template with the parameter names left out
+fn compile_template(template: &str) -> (String, String) {
+    let mut pattern = String::from("^");
+    let mut shape = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => panic!("unclosed parameter in route {}", template)
+        };
+        pattern.push_str(&regex::escape(&rest[..start]));
+        shape.push_str(&rest[..start]);
+        let mut param = rest[start + 1..end].splitn(2, ':');
+        let name = param.next().unwrap_or("");
+        let constraint = param.next().unwrap_or(ANY_SEGMENT);
+        pattern.push_str(&format!("(?P<{}>{})", name, constraint));
+        shape.push_str(&format!("{{:{}}}", constraint));
+        rest = &rest[end + 1..];
+    }
+    pattern.push_str(&regex::escape(rest));
+    pattern.push('$');
+    shape.push_str(rest);
+    (pattern, shape)
+}
+
 
 pub struct NimoolRouter {
-    routes: Vec<Route>
+    routes: Vec<Route>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    body_limits: BodyLimits,
 }
 
 impl NimoolRouter {
     pub fn new() -> Self {
         Self {
-            routes: Vec::new()
+            routes: Vec::new(),
+            middlewares: Vec::new(),
+            body_limits: BodyLimits::default(),
         }
     }
 
+    pub fn set_body_limits(&mut self, limits: BodyLimits) {
+        self.body_limits = limits;
+    }
+
     pub fn add_route(&mut self, route: Route) {
         self.routes.push(route);
     }
 
-    pub fn handle_request(&self, req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>) -> ResponseFuture {
-        let path = req.uri().path();
-        for route in &self.routes {
-            if route.is_match(path, req.method()) {
-                return route.handle(req, catalog);
+    /// middlewares run in the order they are added, see `Middleware`
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middlewares.push(Arc::new(middleware));
+    }
+
+    /// routes that match no path an earlier route with the same method does not already match.
+    /// the first one always wins, so these could never be reached.
+    pub fn conflicts(&self) -> Vec<String> {
+        self.routes.iter().enumerate()
+            .filter(|(i, r)| self.routes[..*i].iter().any(|earlier| earlier.subsumes(r)))
+            .map(|(_, r)| format!("{} {}", r.method, r.template))
+            .collect()
+    }
+
+    /// the methods of all routes matching `path`, HEAD comes with a safe GET and OPTIONS is
+    /// always there
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let mut allowed: Vec<Method> = Vec::new();
+        for route in self.routes.iter().filter(|r| r.pattern.is_match(path)) {
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method.clone());
+            }
+        }
+        if allowed.is_empty() {
+            return allowed;
+        }
+        if self.head_route(path).is_some() && !allowed.contains(&Method::HEAD) {
+            allowed.push(Method::HEAD);
+        }
+        allowed.push(Method::OPTIONS);
+        allowed
+    }
+
+    /// runs the request through the middlewares and the handler. a handler that fails without a
+    /// response skips the `after` hooks, hyper answers with a bare 500 then.
+    pub fn handle_request(&self, mut req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>) -> ResponseFuture {
+        let mut ctx = Context::new(&req);
+        let mut ran = Vec::with_capacity(self.middlewares.len());
+        let mut answered = None;
+        for m in &self.middlewares {
+            ran.push(m.clone());
+            answered = m.before(&mut req, &mut ctx);
+            if answered.is_some() {
+                break;
+            }
+        }
+        let resp = match answered {
+            Some(resp) => Box::new(ok(resp)),
+            None => self.dispatch(req, catalog)
+        };
+        Box::new(resp.map(move |mut resp| {
+            for m in ran.iter().rev() {
+                m.after(&ctx, &mut resp);
+            }
+            resp
+        }))
+    }
+
+    /// the GET route answering HEAD requests to `path`, if it is safe to run for them
+    fn head_route(&self, path: &str) -> Option<&Route> {
+        self.routes.iter().find(|r| r.is_match(path, &Method::GET)).filter(|r| r.safe)
+    }
+
+    fn dispatch(&self, req: Request<Body>, catalog: &IndexCatalog<DummyIntoFieldType>) -> ResponseFuture {
+        let path = req.uri().path().to_string();
+        if let Some(route) = self.routes.iter().find(|r| r.is_match(&path, req.method())) {
+            return route.handle(req, catalog, &self.body_limits);
+        }
+        //HEAD is a GET without the body, the body is still read to tell its length
+        if req.method() == Method::HEAD {
+            if let Some(route) = self.head_route(&path) {
+                return Box::new(route.handle(req, catalog, &self.body_limits).and_then(|resp| {
+                    let (mut parts, body) = resp.into_parts();
+                    body.concat2().map_err(|e| Box::new(e) as GenericError).map(move |body| {
+                        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+                        Response::from_parts(parts, Body::empty())
+                    })
+                }));
             }
         }
-        let mut resp = Response::new(Body::empty());
-        *resp.status_mut() = StatusCode::NOT_FOUND;
+        let allowed = self.allowed_methods(&path);
+        if allowed.is_empty() {
+            let body = json!({ "error": { "type": "route_not_found", "reason": format!("no route for {} {}", req.method(), path) } });
+            return Box::new(ok(handler::json_response(StatusCode::NOT_FOUND, &body)));
+        }
+        let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+        let allow = allow.join(", ");
+        let mut resp = if req.method() == Method::OPTIONS {
+            Response::new(Body::empty())
+        } else {
+            let body = json!({ "error": { "type": "method_not_allowed", "reason": format!("{} is not allowed on {}, allowed are {}", req.method(), path, allow) } });
+            handler::json_response(StatusCode::METHOD_NOT_ALLOWED, &body)
+        };
+        if let Ok(v) = HeaderValue::from_str(&allow) {
+            resp.headers_mut().insert(ALLOW, v);
+        }
         Box::new(ok(resp))
     }
 }
 
+#[cfg(test)]
 mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use regex::Regex;
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    use hyper::header::{ALLOW, CONTENT_LENGTH};
+    use futures::{future, Future, Stream};
+    use futures::future::ok;
+    use crate::db::IndexCatalog;
+    use crate::db::testing::with_app_conf;
+    use crate::DummyIntoFieldType;
+    use super::{compile_template, Params, NimoolRouter, Route, ResponseFuture, GenericError};
 
     #[test]
     fn regex_test() {
@@ -118,6 +397,87 @@ mod test {
         assert_eq!(caps.get(1).unwrap().as_str(), "somename");
         assert_eq!(re.is_match("sdfsfsf"), false);
     }
-}
 
+    #[test]
+    fn test_route_params() {
+        let (pattern, shape) = compile_template(r"/nimool/index/{index:\w+}/_doc/{id}");
+        assert_eq!(shape, r"/nimool/index/{:\w+}/_doc/{:[^/]+}");
+        let re = Regex::new(&pattern).unwrap();
+        let params = Params::new(&re, "/nimool/index/books/_doc/a%2Fb", Some("commit&size=10&size=20&q=a+b")).unwrap();
+        assert_eq!(&params["index"], "books");
+        assert_eq!(&params["id"], "a/b");
+        assert_eq!(params.query("commit"), Some(""));
+        assert_eq!(params.query("size"), Some("10"));
+        assert_eq!(params.query("q"), Some("a b"));
+        assert!(Params::new(&re, "/nimool/index/a.b/_doc/1", None).is_none());
+        assert!(Params::new(&re, "/nimool/index/books/_doc/", None).is_none());
+    }
+
+    fn noop(_: Request<Body>, _: &IndexCatalog<DummyIntoFieldType>, _: Params) -> ResponseFuture {
+        Box::new(ok(Response::new(Body::empty())))
+    }
+
+    #[test]
+    fn test_route_conflicts() {
+        let mut router = NimoolRouter::new();
+        router.add_route(Route::new_get("/nimool/index/{index}", noop));
+        router.add_route(Route::new_put("/nimool/index/{index}", noop));
+        router.add_route(Route::new_get("/nimool/index/{name}", noop));
+        router.add_route(Route::new_get(r"/nimool/index/{index:\w+}", noop));
+        router.add_route(Route::new_get(r"/nimool/index/_all", noop));
+        router.add_route(Route::new_get(r"/nimool/{index:\w+}/_doc", noop));
+        router.add_route(Route::new_get(r"/nimool/books/{id}", noop));
+        router.add_route(Route::new_get(r"/nimool/{index}/{id}", noop));
+        assert_eq!(router.conflicts(), vec![
+            "GET /nimool/index/{name}".to_string(),
+            r"GET /nimool/index/{index:\w+}".to_string(),
+            "GET /nimool/index/_all".to_string(),
+        ]);
+    }
+
+    static GREETED: AtomicUsize = AtomicUsize::new(0);
 
+    fn greet(_: Request<Body>, _: &IndexCatalog<DummyIntoFieldType>, _: Params) -> ResponseFuture {
+        GREETED.fetch_add(1, Ordering::SeqCst);
+        Box::new(ok(Response::new(Body::from("hello"))))
+    }
+
+    #[test]
+    fn test_head_options_and_not_allowed() {
+        let mut router = NimoolRouter::new();
+        router.add_route(Route::new_get("/nimool/_indexes", greet).safe());
+        router.add_route(Route::new_get("/nimool/index/{index}", greet));
+        router.add_route(Route::new_put("/nimool/index/{index}", noop));
+        let responses = with_app_conf(move |app_conf| {
+            let catalog: IndexCatalog<DummyIntoFieldType> = IndexCatalog::new(Arc::new(app_conf));
+            let requests = vec![
+                (Method::HEAD, "/nimool/_indexes"),
+                (Method::HEAD, "/nimool/index/books"),
+                (Method::OPTIONS, "/nimool/index/books"),
+                (Method::DELETE, "/nimool/index/books"),
+                (Method::GET, "/nimool/nope"),
+            ];
+            let calls: Vec<_> = requests.into_iter().map(|(method, path)| {
+                let req = Request::builder().method(method).uri(path).body(Body::empty()).unwrap();
+                router.handle_request(req, &catalog).and_then(|resp| {
+                    let (parts, body) = resp.into_parts();
+                    let header = |name| parts.headers.get(name).map(|v| v.to_str().unwrap().to_string());
+                    let head = (parts.status, header(ALLOW), header(CONTENT_LENGTH));
+                    body.concat2().map_err(|e| Box::new(e) as GenericError).map(move |body| (head, body.len()))
+                })
+            }).collect();
+            future::join_all(calls)
+        }).unwrap();
+        let allow = Some("GET, PUT, OPTIONS".to_string());
+        //a safe GET answers HEAD with the length of the body it would have sent
+        assert_eq!(responses[0], ((StatusCode::OK, None, Some("5".to_string())), 0));
+        //the other GET is not run for HEAD, it is not offered either
+        assert_eq!((responses[1].0).0, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!((responses[1].0).1, allow);
+        assert_eq!(GREETED.load(Ordering::SeqCst), 1);
+        assert_eq!(responses[2], ((StatusCode::OK, allow.clone(), None), 0));
+        assert_eq!((responses[3].0).0, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!((responses[3].0).1, allow);
+        assert_eq!((responses[4].0).0, StatusCode::NOT_FOUND);
+    }
+}